@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS `discs` (
+    disc INTEGER NOT NULL,
+    project TEXT NOT NULL, -- top-level directory, e.g. Repository, AnthonOS4
+    path TEXT NOT NULL, -- relative to the root of the disc, including the project
+    md5 TEXT NOT NULL,
+    PRIMARY KEY (disc, path)
+);
+
+CREATE INDEX IF NOT EXISTS `disc_md5` ON `discs` (md5);
@@ -1,12 +1,22 @@
 CREATE TABLE IF NOT EXISTS `packages` (
     package TEXT NOT NULL,
-    sha256 TEXT NOT NULL PRIMARY KEY, -- sha256sum is most likely to be unique
+    sha256 TEXT NOT NULL,
     size INTEGER NOT NULL,
     architecture TEXT NOT NULL,
     filename TEXT NOT NULL UNIQUE,
     version TEXT NOT NULL,
     repo TEXT NOT NULL,
-    retire_date DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    retire_date DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status TEXT NOT NULL DEFAULT 'archived', -- archived, already-archived, restored, rolled-back
+    location TEXT, -- where the existing copy of an already-archived package lives
+    PRIMARY KEY (sha256, filename) -- the same file may be in more than one repository
 );
 
 CREATE UNIQUE INDEX IF NOT EXISTS `package_version` ON `packages` (package, version, architecture, repo, sha256);
+
+CREATE TABLE IF NOT EXISTS `history` (
+    event TEXT NOT NULL, -- rolled-back
+    date DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    packages INTEGER NOT NULL, -- number of packages affected
+    missing INTEGER NOT NULL DEFAULT 0 -- number of packages with no copy left
+);
@@ -191,7 +191,8 @@ pub async fn update_abbs_database(pool: &Pool<Postgres>, abbs_dir: &dyn AsRef<Pa
 fn test_scan_tree() -> Result<()> {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
+        .try_init()
+        .ok();
     let abbs_dir = if let Ok(v) = std::env::var("ABBS_DIR") {
         v
     } else {
@@ -207,7 +208,8 @@ fn test_scan_tree() -> Result<()> {
 async fn test_update_db() -> Result<()> {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
+        .try_init()
+        .ok();
     let abbs_dir = if let Ok(v) = std::env::var("ABBS_DIR") {
         v
     } else {
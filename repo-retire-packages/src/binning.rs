@@ -0,0 +1,382 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use byte_unit::Byte;
+use bytesize::ByteSize;
+use log::{info, warn};
+
+use crate::db::load_package_names;
+use crate::image::{dir_overhead, file_overhead, IMAGE_RESERVE};
+use crate::manifest::{manifest_cost, write_manifests, MANIFEST_RESERVE};
+
+/// Both ISO 9660 and UDF allocate space in 2048-byte logical sectors.
+pub const SECTOR_SIZE: u64 = 2048;
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Path relative to the root of the volume
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Volume {
+    pub files: Vec<FileEntry>,
+    /// Space taken on the media, including the manifest entries and the
+    /// file system structures
+    pub used: u64,
+    dirs: HashSet<PathBuf>,
+}
+
+#[inline]
+fn sector_aligned(size: u64) -> u64 {
+    size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE
+}
+
+impl FileEntry {
+    /// Space the file takes on a volume, in whole sectors, plus its entries
+    /// in the manifests and the file system structures.
+    fn cost(&self) -> u64 {
+        sector_aligned(self.size) + manifest_cost(&self.path) + file_overhead(&self.path, self.size)
+    }
+}
+
+impl Volume {
+    /// Space the files would take on this volume, including the directories
+    /// that do not exist on it yet.
+    fn cost_of(&self, files: &[FileEntry]) -> u64 {
+        let mut new_dirs = HashSet::new();
+        let mut cost = 0;
+        for file in files {
+            cost += file.cost();
+            for dir in file.path.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || self.dirs.contains(dir) {
+                    break;
+                }
+                if new_dirs.insert(dir) {
+                    cost += dir_overhead(dir);
+                }
+            }
+        }
+
+        cost
+    }
+
+    fn add(&mut self, mut files: Vec<FileEntry>, cost: u64) {
+        for file in files.iter() {
+            for dir in file.path.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || !self.dirs.insert(dir.to_owned()) {
+                    break;
+                }
+            }
+        }
+        self.used += cost;
+        self.files.append(&mut files);
+    }
+}
+
+pub fn parse_volume_size(size: &str) -> Result<u64> {
+    let bytes = Byte::from_str(size)
+        .with_context(|| format!("when parsing volume size {}", size))?
+        .get_bytes();
+    let bytes = u64::try_from(bytes).context("Volume size is too large")?;
+    // round down so that a volume never exceeds what the user asked for
+    let bytes = bytes / SECTOR_SIZE * SECTOR_SIZE;
+    if bytes == 0 {
+        bail!("Volume size must be at least {} bytes", SECTOR_SIZE);
+    }
+
+    Ok(bytes)
+}
+
+fn collect_files(input: &Path, project: &str) -> Result<Vec<FileEntry>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(input).same_file_system(true) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            warn!("Skipping non-regular file: {}", entry.path().display());
+            continue;
+        }
+        let path = Path::new(project).join(entry.path().strip_prefix(input)?);
+        let size = entry.metadata()?.len();
+        files.push(FileEntry { path, size });
+    }
+
+    Ok(files)
+}
+
+#[derive(Debug, Default)]
+pub struct FileGroup {
+    /// Name of the package, or the path for files that are not packages
+    pub name: String,
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Debug)]
+pub struct SplitGroup {
+    pub name: String,
+    /// Indices of the volumes holding the files of this group
+    pub volumes: Vec<usize>,
+}
+
+/// Parses the package name from a file name like `name_version_arch.deb`.
+fn parse_package_name(path: &Path) -> Option<&str> {
+    let filename = path.file_name()?.to_str()?;
+    let stem = filename.strip_suffix(".deb")?;
+    let (name, _) = stem.split_once('_')?;
+
+    Some(name)
+}
+
+/// Groups the files by package, so that all versions and architectures of
+/// a package can be kept on the same volume. `names` maps file names
+/// (without directories) to package names.
+pub fn group_files(files: Vec<FileEntry>, names: &HashMap<String, String>) -> Vec<FileGroup> {
+    let mut groups: HashMap<String, FileGroup> = HashMap::new();
+    for file in files {
+        let name = file
+            .path
+            .file_name()
+            .and_then(|p| p.to_str())
+            .and_then(|p| names.get(p))
+            .map(|n| n.as_str())
+            .or_else(|| parse_package_name(&file.path))
+            .map(|n| n.to_owned())
+            .unwrap_or_else(|| file.path.display().to_string());
+        let group = groups.entry(name).or_insert_with_key(|name| FileGroup {
+            name: name.clone(),
+            files: Vec::new(),
+        });
+        group.files.push(file);
+    }
+
+    groups.into_values().collect()
+}
+
+/// Puts the files into the first volume with enough space left, or into a
+/// new one. Returns the index of the volume.
+fn first_fit(
+    volumes: &mut Vec<Volume>,
+    from: usize,
+    files: Vec<FileEntry>,
+    capacity: u64,
+) -> usize {
+    let found = volumes[from..].iter().enumerate().find_map(|(i, v)| {
+        let cost = v.cost_of(&files);
+        (v.used + cost <= capacity).then_some((from + i, cost))
+    });
+    let (index, cost) = found.unwrap_or_else(|| {
+        volumes.push(Volume::default());
+        (volumes.len() - 1, Volume::default().cost_of(&files))
+    });
+    volumes[index].add(files, cost);
+
+    index
+}
+
+/// Packs the groups into as few volumes as possible (first-fit decreasing).
+/// A group is only split across volumes when it does not fit into one
+/// volume on its own, in which case it is placed into fresh volumes to
+/// span as few of them as possible.
+pub fn pack_groups(
+    groups: Vec<FileGroup>,
+    capacity: u64,
+) -> Result<(Vec<Volume>, Vec<SplitGroup>)> {
+    let mut volumes: Vec<Volume> = Vec::new();
+    let mut split = Vec::new();
+    let mut groups = groups
+        .into_iter()
+        .map(|g| (Volume::default().cost_of(&g.files), g))
+        .collect::<Vec<_>>();
+    // sort by name as well to make the result reproducible
+    groups.sort_unstable_by(|(a_size, a), (b_size, b)| {
+        b_size.cmp(a_size).then_with(|| a.name.cmp(&b.name))
+    });
+    for (size, mut group) in groups {
+        if size <= capacity {
+            first_fit(&mut volumes, 0, group.files, capacity);
+            continue;
+        }
+        let first = volumes.len();
+        let mut spanned = Vec::new();
+        group
+            .files
+            .sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        for file in group.files {
+            let file = vec![file];
+            if Volume::default().cost_of(&file) > capacity {
+                let file = &file[0];
+                bail!(
+                    "{} ({}) does not fit into a volume of {}",
+                    file.path.display(),
+                    ByteSize::b(file.size).to_string_as(true),
+                    ByteSize::b(capacity).to_string_as(true)
+                );
+            }
+            let index = first_fit(&mut volumes, first, file, capacity);
+            if !spanned.contains(&index) {
+                spanned.push(index);
+            }
+        }
+        split.push(SplitGroup {
+            name: group.name,
+            volumes: spanned,
+        });
+    }
+    for volume in volumes.iter_mut() {
+        volume.files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    Ok((volumes, split))
+}
+
+/// Hard-links the file into the volume when possible, copies it otherwise.
+fn place_file(src: &Path, dst: &Path) -> Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("when creating directory {}", parent.display()))?;
+    }
+    if std::fs::hard_link(src, dst).is_err() {
+        std::fs::copy(src, dst).with_context(|| format!("when copying {}", src.display()))?;
+    }
+
+    Ok(())
+}
+
+pub fn volume_name(number: usize) -> String {
+    format!("disc-{}", number)
+}
+
+pub fn binning_action<P: AsRef<Path>>(
+    input: P,
+    output: P,
+    size: &str,
+    start: usize,
+    labels: &[String],
+    project: &str,
+) -> Result<()> {
+    let input = input.as_ref();
+    let output = output.as_ref();
+    let capacity = parse_volume_size(size)?;
+    if capacity <= MANIFEST_RESERVE + IMAGE_RESERVE {
+        bail!("Volume size is too small to hold the manifests");
+    }
+    let mut names = HashMap::new();
+    for db_path in labels {
+        info!("Loading package names from {} ...", db_path);
+        let labels =
+            load_package_names(db_path).with_context(|| format!("when reading {}", db_path))?;
+        for (filename, package) in labels {
+            if let Some(name) = Path::new(&filename).file_name().and_then(|n| n.to_str()) {
+                names.insert(name.to_owned(), package);
+            }
+        }
+    }
+    info!("Scanning {} ...", input.display());
+    let files = collect_files(input, project)?;
+    let total_size = files.iter().fold(0, |t, x| t + x.size);
+    info!(
+        "{} files, {} total",
+        files.len(),
+        ByteSize::b(total_size).to_string_as(true)
+    );
+    let groups = group_files(files, &names);
+    info!("{} packages", groups.len());
+    let (volumes, split) = pack_groups(groups, capacity - MANIFEST_RESERVE - IMAGE_RESERVE)?;
+    info!(
+        "Packing into {} volumes of {} each ...",
+        volumes.len(),
+        ByteSize::b(capacity).to_string_as(true)
+    );
+    if !split.is_empty() {
+        warn!(
+            "{} packages do not fit into a single volume and have been split:",
+            split.len()
+        );
+        for group in split.iter() {
+            let volumes = group
+                .volumes
+                .iter()
+                .map(|i| volume_name(start + i))
+                .collect::<Vec<_>>();
+            warn!(" - {}: {}", group.name, volumes.join(", "));
+        }
+    }
+    for i in 0..volumes.len() {
+        let volume_dir = output.join(volume_name(start + i));
+        if volume_dir.exists() {
+            bail!("Volume directory {} already exists", volume_dir.display());
+        }
+    }
+    for (i, volume) in volumes.iter().enumerate() {
+        let volume_dir = output.join(volume_name(start + i));
+        info!(
+            "[{}/{}] {}: {} files, {}",
+            i + 1,
+            volumes.len(),
+            volume_dir.display(),
+            volume.files.len(),
+            ByteSize::b(volume.used).to_string_as(true)
+        );
+        for file in volume.files.iter() {
+            let src = input.join(file.path.strip_prefix(project)?);
+            place_file(&src, &volume_dir.join(&file.path))?;
+        }
+        info!("Generating manifests for {} ...", volume_dir.display());
+        write_manifests(&volume_dir, start + i)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_pack_groups() -> Result<()> {
+    let file = |path: &str, size: u64| FileEntry {
+        path: PathBuf::from(path),
+        size,
+    };
+    let capacity = parse_volume_size("100KiB")?;
+    assert_eq!(capacity, 102400);
+    let files = vec![
+        file("a/a_1_amd64.deb", 30000),
+        file("a/a_2_amd64.deb", 1),
+        file("b/b_1_amd64.deb", 20480),
+        file("c/c_1_amd64.deb", 20000),
+        file("c/c_2_arm64.deb", 10000),
+        file("d/libd_1_amd64.deb", 40960),
+        file("d/libd_2_amd64.deb", 40960),
+        file("d/libd_3_amd64.deb", 40960),
+    ];
+    // the labels database names the package, unlike its file name
+    let names = (1..=3)
+        .map(|i| (format!("libd_{}_amd64.deb", i), "d".to_owned()))
+        .collect::<HashMap<_, _>>();
+    let groups = group_files(files, &names);
+    assert_eq!(groups.len(), 4);
+    let (volumes, split) = pack_groups(groups, capacity)?;
+    for volume in volumes.iter() {
+        assert!(volume.used <= capacity);
+    }
+    assert_eq!(volumes.iter().map(|v| v.files.len()).sum::<usize>(), 8);
+    // every group that fits stays on one volume
+    for prefix in ["a/", "b/", "c/"] {
+        let holding = volumes
+            .iter()
+            .filter(|v| v.files.iter().any(|f| f.path.starts_with(prefix)))
+            .count();
+        assert_eq!(holding, 1);
+    }
+    assert_eq!(split.len(), 1);
+    assert_eq!(split[0].name, "d");
+    assert_eq!(split[0].volumes.len(), 2);
+    assert!(pack_groups(
+        group_files(vec![file("huge.deb", 102400)], &names),
+        capacity
+    )
+    .is_err());
+    Ok(())
+}
@@ -0,0 +1,633 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::db::{
+    load_archived_checksums, load_disc_entries, save_disc_entries, search_archived_packages,
+    search_disc_entries, DiscEntry, PackageMeta,
+};
+use crate::manifest::{md5_file, md5_name, parse_md5, parse_tree, sha256_file, tree_name};
+use crate::version::compare_versions;
+
+/// Finds the numbers of all the discs with a checksum list in the directory.
+pub fn list_discs(dir: &Path) -> Result<Vec<usize>> {
+    let mut discs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(number) = name
+            .strip_prefix("disc-")
+            .and_then(|n| n.strip_suffix(".md5"))
+            .and_then(|n| n.parse().ok())
+        {
+            discs.push(number);
+        }
+    }
+    discs.sort_unstable();
+
+    Ok(discs)
+}
+
+/// Checks the tree listing of the disc against its checksum list, and
+/// returns the number of problems found.
+fn check_tree(dir: &Path, disc: usize, checksums: &[(String, String)]) -> Result<usize> {
+    let tree_path = dir.join(tree_name(disc));
+    if !tree_path.is_file() {
+        warn!("disc-{}: {} is missing", disc, tree_name(disc));
+        return Ok(1);
+    }
+    let listing = parse_tree(&std::fs::read_to_string(&tree_path)?);
+    let leaves = listing.leaves.iter().collect::<HashSet<_>>();
+    let md5_file = md5_name(disc);
+    let summed = checksums.iter().map(|(_, p)| p).collect::<HashSet<_>>();
+    let mut problems = 0;
+    let mut missing = leaves
+        .iter()
+        .filter(|p| !summed.contains(**p) && ***p != md5_file)
+        .collect::<Vec<_>>();
+    missing.sort_unstable();
+    // Empty directories are not distinguishable from files in the listing,
+    // but they are counted in its last line.
+    let empty_dirs = listing
+        .summary
+        .map(|(dirs, _)| dirs.saturating_sub(listing.dirs))
+        .unwrap_or_default();
+    if missing.len() != empty_dirs {
+        problems += missing.len();
+        warn!(
+            "disc-{}: {} entries in the tree have no checksum ({} of them may be empty directories):",
+            disc,
+            missing.len(),
+            empty_dirs
+        );
+        for path in missing {
+            warn!(" - {}", path);
+        }
+    }
+    let mut extra = summed
+        .iter()
+        .filter(|p| !leaves.contains(**p))
+        .collect::<Vec<_>>();
+    extra.sort_unstable();
+    if !extra.is_empty() {
+        problems += extra.len();
+        warn!(
+            "disc-{}: {} checksummed files are missing from the tree:",
+            disc,
+            extra.len()
+        );
+        for path in extra {
+            warn!(" - {}", path);
+        }
+    }
+
+    Ok(problems)
+}
+
+pub fn import_discs_action<P: AsRef<Path>>(input: P, db_path: P) -> Result<()> {
+    let input = input.as_ref();
+    let discs = list_discs(input)?;
+    info!(
+        "Importing {} discs from {} ...",
+        discs.len(),
+        input.display()
+    );
+    let mut total = 0;
+    let mut problems = 0;
+    for disc in discs {
+        let md5_path = input.join(md5_name(disc));
+        let checksums = parse_md5(&std::fs::read_to_string(&md5_path)?)
+            .with_context(|| format!("when parsing {}", md5_path.display()))?;
+        if checksums.iter().any(|(_, p)| *p == md5_name(disc)) {
+            problems += 1;
+            warn!(
+                "disc-{}: {} contains its own checksum",
+                disc,
+                md5_name(disc)
+            );
+        }
+        problems += check_tree(input, disc, &checksums)?;
+        // the manifests at the root are not part of any project
+        let entries = checksums
+            .into_iter()
+            .filter_map(|(md5, path)| {
+                let (project, _) = path.split_once('/')?;
+                Some(DiscEntry {
+                    project: project.to_owned(),
+                    path,
+                    md5,
+                })
+            })
+            .collect::<Vec<_>>();
+        info!("disc-{}: {} files", disc, entries.len());
+        total += entries.len();
+        save_disc_entries(db_path.as_ref(), disc as i64, &entries)?;
+    }
+    info!("Imported {} files, {} problems found", total, problems);
+
+    Ok(())
+}
+
+/// Copies of packages already in cold storage, from the archive databases
+/// of earlier batches and the disc catalog.
+#[derive(Debug, Default)]
+pub struct ArchiveIndex {
+    /// sha256 -> path of the archived copy
+    checksums: HashMap<String, PathBuf>,
+    /// file name -> (disc, path, md5)
+    discs: HashMap<String, Vec<(i64, String, String)>>,
+}
+
+impl ArchiveIndex {
+    /// Loads the archive databases and the catalog, leaving out the
+    /// database of the current batch.
+    pub fn load(labels: &[String], catalog: Option<&str>, current: &Path) -> Result<Self> {
+        let mut index = Self::default();
+        let current = std::fs::canonicalize(current).ok();
+        for db_path in find_label_dbs(labels)? {
+            if std::fs::canonicalize(&db_path).ok() == current && current.is_some() {
+                continue;
+            }
+            let archive_dir = db_path.parent().unwrap_or(Path::new("."));
+            let checksums = load_archived_checksums(&db_path)
+                .with_context(|| format!("when reading {}", db_path.display()))?;
+            info!(
+                "{} archived packages in {}",
+                checksums.len(),
+                db_path.display()
+            );
+            for (sha256, filename) in checksums {
+                index.checksums.insert(sha256, archive_dir.join(filename));
+            }
+        }
+        if let Some(catalog) = catalog {
+            let entries =
+                load_disc_entries(catalog).with_context(|| format!("when reading {}", catalog))?;
+            info!("{} files on the discs in {}", entries.len(), catalog);
+            for (disc, e) in entries {
+                let name = file_name(&e.path).to_owned();
+                index
+                    .discs
+                    .entry(name)
+                    .or_default()
+                    .push((disc, e.path, e.md5));
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Returns where the existing copy of the package lives, if any. A copy
+    /// from an earlier batch counts only if it is still there, intact, as
+    /// the batch may have been burned and removed since. The discs only have
+    /// MD5 checksums, so the file in the pool is hashed if a file of the same
+    /// name is on a disc.
+    pub fn locate(&self, package: &PackageMeta, pool: &Path) -> Result<Option<String>> {
+        if let Some(path) = self.checksums.get(&package.sha256) {
+            match sha256_file(path) {
+                Ok(sha256) if sha256 == package.sha256 => {
+                    return Ok(Some(path.display().to_string()))
+                }
+                Ok(_) => warn!(
+                    "{} is archived at {}, but it was changed",
+                    package.filename,
+                    path.display()
+                ),
+                Err(_) => warn!(
+                    "{} is archived at {}, but it is gone",
+                    package.filename,
+                    path.display()
+                ),
+            }
+        }
+        let Some(copies) = self.discs.get(file_name(&package.filename)) else {
+            return Ok(None);
+        };
+        let path = pool.join(&package.filename);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let md5 = md5_file(&path).with_context(|| format!("when hashing {}", path.display()))?;
+
+        Ok(copies
+            .iter()
+            .find(|(_, _, sum)| *sum == md5)
+            .map(|(disc, path, _)| format!("disc-{}:./{}", disc, path)))
+    }
+}
+
+/// Returns the number of the disc in a location made by
+/// [`ArchiveIndex::locate`] for a copy on a disc, like
+/// `disc-48:./Repository/...`.
+pub fn disc_of(location: &str) -> Option<i64> {
+    location
+        .strip_prefix("disc-")?
+        .split_once(":./")?
+        .0
+        .parse()
+        .ok()
+}
+
+/// An archived version of a package, with the discs holding it.
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: Option<String>,
+    pub retire_date: Option<String>,
+    /// Date of the retirement batch, from the name of its `labels-DATE.db`
+    pub batch: Option<String>,
+    pub discs: Vec<i64>,
+    pub filename: String,
+}
+
+fn is_label_db(name: &str) -> bool {
+    name.starts_with("labels-") && name.ends_with(".db")
+}
+
+/// Finds the `labels-*.db` files, looking into the directories given and
+/// their `archive-*` batch directories, but no deeper, so that the pool and
+/// the logs next to the batches are not scanned.
+pub fn find_label_dbs(labels: &[String]) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for path in labels {
+        if Path::new(path).is_file() {
+            found.push(PathBuf::from(path));
+            continue;
+        }
+        if !Path::new(path).exists() {
+            warn!("{} does not exist, skipping", path);
+            continue;
+        }
+        let walker = walkdir::WalkDir::new(path)
+            .min_depth(1)
+            .max_depth(2)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                let name = entry.file_name().to_string_lossy();
+                if entry.file_type().is_dir() {
+                    entry.depth() == 1 && name.starts_with("archive-")
+                } else {
+                    is_label_db(&name)
+                }
+            });
+        for entry in walker {
+            let entry = entry.with_context(|| format!("when scanning {}", path))?;
+            if entry.file_type().is_file() {
+                found.push(entry.into_path());
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Splits a Debian package file name into its name, version and architecture.
+fn parse_deb_name(filename: &str) -> Option<(&str, &str, &str)> {
+    let stem = filename.strip_suffix(".deb")?;
+    let (name, rest) = stem.split_once('_')?;
+    let (version, arch) = rest.rsplit_once('_')?;
+
+    Some((name, version, arch))
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Searches the archive databases and the disc catalog for the package.
+/// `name` may be a glob pattern.
+pub fn search_packages(
+    name: &str,
+    version: Option<&str>,
+    arch: Option<&str>,
+    labels: &[String],
+    catalog: Option<&str>,
+) -> Result<Vec<SearchResult>> {
+    // file name -> (discs, path on the first disc)
+    let mut on_discs: HashMap<String, (BTreeSet<i64>, String)> = HashMap::new();
+    if let Some(catalog) = catalog {
+        let entries = search_disc_entries(catalog, name)
+            .with_context(|| format!("when reading {}", catalog))?;
+        for (disc, path) in entries {
+            on_discs
+                .entry(file_name(&path).to_owned())
+                .or_insert_with(|| (BTreeSet::new(), path))
+                .0
+                .insert(disc);
+        }
+    }
+    let mut results = Vec::new();
+    let mut seen = HashSet::new();
+    for db_path in find_label_dbs(labels)? {
+        let batch = db_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.strip_prefix("labels-"))
+            .map(|s| s.to_owned());
+        let packages = search_archived_packages(&db_path, name, version, arch)
+            .with_context(|| format!("when reading {}", db_path.display()))?;
+        for p in packages {
+            let basename = file_name(&p.filename).to_owned();
+            let discs = on_discs
+                .get(&basename)
+                .map(|(discs, _)| discs.iter().copied().collect())
+                .unwrap_or_default();
+            seen.insert(basename);
+            results.push(SearchResult {
+                package: p.package,
+                version: p.version,
+                architecture: p.architecture,
+                repo: Some(p.repo),
+                retire_date: Some(p.retire_date),
+                batch: batch.clone(),
+                discs,
+                filename: p.filename,
+            });
+        }
+    }
+    // packages archived before the labels databases existed
+    for (basename, (discs, path)) in on_discs {
+        if seen.contains(&basename) {
+            continue;
+        }
+        let Some((package, v, a)) = parse_deb_name(&basename) else {
+            continue;
+        };
+        if version.is_some_and(|version| version != v) || arch.is_some_and(|arch| arch != a) {
+            continue;
+        }
+        // Project/<repo>/<letter>/<file>
+        let components = path.split('/').collect::<Vec<_>>();
+        let repo = (components.len() > 3).then(|| components[1..components.len() - 2].join("/"));
+        results.push(SearchResult {
+            package: package.to_owned(),
+            version: v.to_owned(),
+            architecture: a.to_owned(),
+            repo,
+            retire_date: None,
+            batch: None,
+            discs: discs.into_iter().collect(),
+            filename: path,
+        });
+    }
+    results.sort_by(|a, b| {
+        a.package
+            .cmp(&b.package)
+            .then_with(|| compare_versions(&a.version, &b.version))
+            .then_with(|| (&a.architecture, &a.batch).cmp(&(&b.architecture, &b.batch)))
+    });
+
+    Ok(results)
+}
+
+fn print_table(results: &[SearchResult]) {
+    let header = [
+        "PACKAGE", "VERSION", "ARCH", "REPO", "RETIRED", "BATCH", "DISCS",
+    ];
+    let rows = results
+        .iter()
+        .map(|r| {
+            let discs = r
+                .discs
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            [
+                r.package.clone(),
+                r.version.clone(),
+                r.architecture.clone(),
+                r.repo.clone().unwrap_or_else(|| "-".to_owned()),
+                r.retire_date.clone().unwrap_or_else(|| "-".to_owned()),
+                r.batch.clone().unwrap_or_else(|| "-".to_owned()),
+                if discs.is_empty() {
+                    "-".to_owned()
+                } else {
+                    discs
+                },
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = header.map(|h| h.len());
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    print_row(header.to_vec());
+    for row in &rows {
+        print_row(row.iter().map(|c| c.as_str()).collect());
+    }
+}
+
+pub fn search_action(
+    name: &str,
+    version: Option<&str>,
+    arch: Option<&str>,
+    labels: &[String],
+    catalog: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let results = search_packages(name, version, arch, labels, catalog)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else if !results.is_empty() {
+        print_table(&results);
+    }
+    if results.is_empty() {
+        bail!("No archived package matches {}", name);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_import_discs() -> Result<()> {
+    let root = crate::testing::TempDir::new("discs")?;
+    let discs = root.join("Disc");
+    std::fs::create_dir_all(&discs)?;
+    for name in ["disc-7.md5", "disc-7.tree", "disc-48.md5", "disc-48.tree"] {
+        std::fs::copy(Path::new("../Disc").join(name), discs.join(name))?;
+    }
+    let db_path = root.join("catalog.db");
+    import_discs_action(&discs, &db_path)?;
+    import_discs_action(&discs, &db_path)?;
+    let conn = rusqlite::Connection::open(&db_path)?;
+    let count: usize = conn.query_row("SELECT count(*) FROM discs", [], |r| r.get(0))?;
+    let disc_48: usize = conn.query_row("SELECT count(*) FROM discs WHERE disc = 48", [], |r| {
+        r.get(0)
+    })?;
+    let projects: usize = conn.query_row("SELECT count(DISTINCT project) FROM discs", [], |r| {
+        r.get(0)
+    })?;
+    drop(root);
+    // every checksum but the one of the tree, imported only once
+    let disc_7 = std::fs::read_to_string("../Disc/disc-7.md5")?
+        .lines()
+        .count()
+        - 1;
+    assert_eq!(disc_48, 114);
+    assert_eq!(count, disc_7 + disc_48);
+    assert_eq!(projects, 1);
+    assert_eq!(list_discs(Path::new("../Disc"))?.len(), 48);
+    // escaped spaces and empty directories in the listing
+    for disc in [6, 7] {
+        let md5 = std::fs::read_to_string(Path::new("../Disc").join(md5_name(disc)))?;
+        assert_eq!(
+            check_tree(Path::new("../Disc"), disc, &parse_md5(&md5)?)?,
+            0
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_search_packages() -> Result<()> {
+    let root = crate::testing::TempDir::new("search")?;
+    let batch = root.join("archive-20240101");
+    std::fs::create_dir_all(&batch)?;
+    let package = |version: &str, arch: &str| crate::db::PackageMeta {
+        sha256: format!("{}-{}", version, arch),
+        size: 1,
+        repo: "amd64/stable".to_owned(),
+        ..crate::testing::package("webkit2gtk", version, arch)
+    };
+    crate::db::save_new_packages(
+        batch.join("labels-20240101.db"),
+        &[package("2.20.0-0", "amd64"), package("2.22.0", "amd64")],
+    )?;
+    // not a batch directory
+    std::fs::create_dir_all(root.join("retire-logs"))?;
+    crate::db::save_new_packages(
+        root.join("retire-logs/labels-20240201.db"),
+        &[package("2.24.0", "amd64")],
+    )?;
+    let catalog = root.join("catalog.db");
+    let entries = std::fs::read_to_string("../Disc/disc-48.md5")?;
+    let entries = parse_md5(&entries)?
+        .into_iter()
+        .filter_map(|(md5, path)| {
+            let (project, _) = path.split_once('/')?;
+            Some(DiscEntry {
+                project: project.to_owned(),
+                path,
+                md5,
+            })
+        })
+        .collect::<Vec<_>>();
+    save_disc_entries(&catalog, 48, &entries)?;
+    let labels = [root.to_string_lossy().into_owned()];
+    let catalog = catalog.to_string_lossy().into_owned();
+    let all = search_packages("webkit2gtk", None, None, &labels, Some(&catalog))?;
+    let first = search_packages(
+        "webkit2gtk",
+        Some("2.20.0-0"),
+        None,
+        &labels,
+        Some(&catalog),
+    )?;
+    let amd64 = search_packages("webkit2gtk", None, Some("amd64"), &labels, Some(&catalog))?;
+    let glob = search_packages("webkit*", Some("2.22.0"), None, &labels, None)?;
+    let stray = search_packages("webkit2gtk", Some("2.24.0"), None, &labels, None)?;
+    drop(root);
+    // 43 files on disc 48, and a version in the batch that is on no disc
+    assert_eq!(all.len(), 44);
+    assert_eq!(first.len(), 5);
+    assert_eq!(first[0].architecture, "amd64");
+    assert_eq!(first[0].discs, vec![48]);
+    assert_eq!(first[0].batch.as_deref(), Some("20240101"));
+    assert_eq!(first[0].repo.as_deref(), Some("amd64/stable"));
+    assert_eq!(first[1].architecture, "arm64");
+    assert_eq!(first[1].repo.as_deref(), Some("stable/main"));
+    assert_eq!(first[1].batch, None);
+    assert!(amd64.iter().all(|r| r.architecture == "amd64"));
+    assert!(amd64
+        .iter()
+        .any(|r| r.version == "2.22.0" && r.discs.is_empty()));
+    assert_eq!(glob.len(), 1);
+    assert!(stray.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_archive_index() -> Result<()> {
+    let root = crate::testing::TempDir::new("index")?;
+    let pool = root.join("debs");
+    let batch = root.join("archive-20240101");
+    std::fs::create_dir_all(pool.join("pool/stable/main/w"))?;
+    std::fs::create_dir_all(&batch)?;
+    let package = |filename: &str, sha256: &str| crate::db::PackageMeta {
+        sha256: sha256.to_owned(),
+        size: 1,
+        filename: format!("pool/stable/main/w/{}", filename),
+        ..crate::testing::package("webkit2gtk", "2.20.0-0", "amd64")
+    };
+    std::fs::create_dir_all(root.join("pool/stable/main/w"))?;
+    let archived = |filename: &str| -> Result<crate::db::PackageMeta> {
+        let path = root.join("pool/stable/main/w").join(filename);
+        std::fs::write(&path, filename)?;
+        Ok(package(filename, &sha256_file(&path)?))
+    };
+    let earlier = archived("webkit2gtk_2.20.0-0_amd64.deb")?;
+    // burned to a disc and removed from the earlier batch
+    let deleted = archived("webkit2gtk_2.18.0-0_amd64.deb")?;
+    std::fs::remove_file(root.join(&deleted.filename))?;
+    let current = batch.join("labels-20240101.db");
+    crate::db::save_new_packages(
+        root.join("labels-20230101.db"),
+        &[earlier.clone(), deleted.clone()],
+    )?;
+    crate::db::save_new_packages(&current, &[package("webkit2gtk_2.20.2-0_amd64.deb", "now")])?;
+    let catalog = root.join("catalog.db");
+    let in_pool = package("webkit2gtk_2.20.0-0_arm64.deb", "pool");
+    std::fs::write(pool.join(&in_pool.filename), "arm64")?;
+    let changed = package("webkit2gtk_2.20.0-0_armel.deb", "changed");
+    std::fs::write(pool.join(&changed.filename), "armel")?;
+    let entries = [
+        "webkit2gtk_2.20.0-0_arm64.deb",
+        "webkit2gtk_2.20.0-0_armel.deb",
+    ]
+    .into_iter()
+    .map(|name| DiscEntry {
+        project: "Repository".to_owned(),
+        path: format!("Repository/stable/main/w/{}", name),
+        md5: format!("{:x}", <md5::Md5 as md5::Digest>::digest("arm64")),
+    })
+    .collect::<Vec<_>>();
+    save_disc_entries(&catalog, 48, &entries)?;
+    let labels = [root.to_string_lossy().into_owned()];
+    let index = ArchiveIndex::load(&labels, catalog.to_str(), &current)?;
+    let earlier_location = index.locate(&earlier, &pool)?;
+    let deleted_location = index.locate(&deleted, &pool)?;
+    let now_location = index.locate(&package("webkit2gtk_2.20.2-0_amd64.deb", "now"), &pool)?;
+    let in_pool_location = index.locate(&in_pool, &pool)?;
+    let changed_location = index.locate(&changed, &pool)?;
+    let earlier_path = root.join(&earlier.filename);
+    drop(root);
+    assert_eq!(earlier_location, Some(earlier_path.display().to_string()));
+    assert_eq!(deleted_location, None);
+    // the current batch is not an earlier one
+    assert_eq!(now_location, None);
+    assert_eq!(
+        in_pool_location.as_deref(),
+        Some("disc-48:./Repository/stable/main/w/webkit2gtk_2.20.0-0_arm64.deb")
+    );
+    // same name, different contents
+    assert_eq!(changed_location, None);
+    Ok(())
+}
@@ -1,10 +1,10 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser)]
 pub struct RetireArgs {
     /// Path to the aosc-os-abbs tree
-    #[arg(short = 'p', long, required = true)]
-    pub abbs_dir: String,
+    #[arg(short = 'p', long, required_unless_present_any = ["resume", "rollback"])]
+    pub abbs_dir: Option<String>,
 
     /// Wait and inhibit the specified systemd services
     #[arg(short = 't', long)]
@@ -18,34 +18,239 @@ pub struct RetireArgs {
     #[arg(short = 'k', long, default_value_t = false)]
     pub with_kernel: bool,
 
+    /// Also retire the topic repositories merged into stable
+    #[arg(long, default_value_t = false)]
+    pub topics: bool,
+
+    /// Also retire the -dbg packages left without their base packages
+    #[arg(long, default_value_t = false)]
+    pub orphaned_dbg: bool,
+
     /// Path to the p-vector config file
-    #[arg(short = 'c', long)]
-    pub config: String,
+    #[arg(short = 'c', long, required_unless_present_any = ["resume", "rollback"])]
+    pub config: Option<String>,
 
     /// Path to the output directory
-    #[arg(short = 'o', long)]
-    pub output: String,
+    #[arg(short = 'o', long, required_unless_present_any = ["resume", "rollback"])]
+    pub output: Option<String>,
 
     /// Just print what would be done
     #[arg(short = 'd', long = "dry-run", default_value_t = false)]
     pub dry_run: bool,
 
     /// Save the data to the SQLite database at this path
-    #[arg(short = 'b', long)]
-    pub database: String,
+    #[arg(short = 'b', long, required_unless_present_any = ["resume", "rollback"])]
+    pub database: Option<String>,
+
+    /// Skip the packages in these archive databases, or the labels-*.db in these directories
+    #[arg(short = 'l', long, default_value = "/lookaside/public/archives")]
+    pub labels: Vec<String>,
+
+    /// Skip the packages on the discs in this SQLite catalog database
+    #[arg(long)]
+    pub catalog: Option<String>,
+
+    /// Finish an interrupted retirement from its journal
+    #[arg(long, conflicts_with = "rollback")]
+    pub resume: Option<String>,
+
+    /// Put every package moved by a retirement back in the pool, from its journal
+    #[arg(long)]
+    pub rollback: Option<String>,
+
+    /// Write the plan to this file, as CSV files if its name ends with .csv, or as JSON
+    #[arg(short = 'r', long)]
+    pub report: Option<String>,
+
+    /// Save the plan to this file with --dry-run, or retire only the packages in a saved plan
+    #[arg(long)]
+    pub plan: Option<String>,
+
+    /// Refuse to retire unless the saved plan has this checksum
+    #[arg(long, requires = "plan", conflicts_with = "dry_run")]
+    pub expect_sha256: Option<String>,
+
+    /// Never retire the packages on the protect-list in this TOML file
+    #[arg(long)]
+    pub protect: Option<String>,
+
+    /// Leave out the default protect-list entries
+    #[arg(long, default_value_t = false)]
+    pub no_default_protect: bool,
 }
 
 #[derive(Parser)]
 pub struct BinningArgs {
     /// Path to the input directory
     #[arg(short = 'i', long)]
-    input: String,
+    pub input: String,
     /// Path to the output directory
     #[arg(short = 'o', long)]
-    output: String,
+    pub output: String,
     /// Size of each bin
     #[arg(short = 's', long)]
-    size: String,
+    pub size: String,
+    /// Number of the first volume
+    #[arg(short = 'n', long, default_value_t = 1)]
+    pub start: usize,
+    /// Look up package names from these SQLite databases
+    #[arg(short = 'l', long)]
+    pub labels: Vec<String>,
+    /// Name of the archived project, i.e. the top-level directory on each volume
+    #[arg(short = 'p', long, default_value = "Repository")]
+    pub project: String,
+}
+
+#[derive(Parser)]
+pub struct MkImageArgs {
+    /// Path to the volume directory created by `binning`
+    #[arg(short = 'i', long)]
+    pub input: String,
+    /// Path to the output image
+    #[arg(short = 'o', long)]
+    pub output: String,
+}
+
+#[derive(Parser)]
+pub struct ImportDiscsArgs {
+    /// Path to the directory holding the disc-N.md5 and disc-N.tree files
+    #[arg(short = 'i', long)]
+    pub input: String,
+    /// Path to the SQLite catalog database
+    #[arg(short = 'b', long)]
+    pub database: String,
+}
+
+#[derive(Parser)]
+pub struct SearchArgs {
+    /// Name of the package, may contain glob patterns
+    pub name: String,
+    /// Only show this version
+    #[arg(short = 'v', long)]
+    pub version: Option<String>,
+    /// Only show this architecture
+    #[arg(short = 'a', long)]
+    pub arch: Option<String>,
+    /// Archive databases (labels-*.db), or directories to look for them in
+    #[arg(short = 'l', long, default_value = "/lookaside/public/archives")]
+    pub labels: Vec<String>,
+    /// Path to the SQLite catalog database of the discs
+    #[arg(short = 'b', long)]
+    pub database: Option<String>,
+    /// Print the results as JSON
+    #[arg(short = 'j', long, default_value_t = false)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct DedupReportArgs {
+    /// Path to the directory holding the disc-N.md5 files
+    #[arg(short = 'i', long)]
+    pub input: Option<String>,
+    /// Path to the SQLite catalog database of the discs
+    #[arg(short = 'b', long)]
+    pub database: Option<String>,
+    /// Look up file sizes from these archive databases, or the labels-*.db in these directories
+    #[arg(short = 'l', long, default_value = "/lookaside/public/archives")]
+    pub labels: Vec<String>,
+    /// Look up file sizes from the disc-N directories here
+    #[arg(short = 'r', long)]
+    pub volumes: Option<String>,
+    /// Write the plan to this file instead of the standard output
+    #[arg(short = 'o', long)]
+    pub output: Option<String>,
+    /// Write the plan as JSON
+    #[arg(short = 'j', long, default_value_t = false)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct RestoreArgs {
+    /// Name of the package
+    pub package: String,
+    /// Version of the package
+    pub version: String,
+    /// Only restore this architecture
+    #[arg(short = 'a', long)]
+    pub arch: Option<String>,
+    /// Path to the p-vector config file
+    #[arg(short = 'c', long)]
+    pub config: String,
+    /// Archive databases (labels-*.db), or directories to look for them in
+    #[arg(short = 'l', long, default_value = "/lookaside/public/archives")]
+    pub labels: Vec<String>,
+    /// Just print what would be done
+    #[arg(short = 'd', long = "dry-run", default_value_t = false)]
+    pub dry_run: bool,
+}
+
+#[derive(Parser)]
+pub struct RollbackArgs {
+    /// Path to the archive database (labels-*.db) of the batch
+    #[arg(short = 'b', long)]
+    pub database: String,
+    /// Path to the archive directory of the batch, defaults to the one holding the database
+    #[arg(short = 'i', long)]
+    pub input: Option<String>,
+    /// Path to the p-vector config file
+    #[arg(short = 'c', long)]
+    pub config: String,
+    /// Just print what would be done
+    #[arg(short = 'd', long = "dry-run", default_value_t = false)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum RunAction {
+    /// Perform a dry-run instead of performing the retirement
+    #[value(alias = "d", alias = "dry")]
+    Dryrun,
+    /// Do the real thing
+    #[value(alias = "r", alias = "ret")]
+    Retire,
+}
+
+#[derive(Parser)]
+#[command(
+    after_help = "You MUST perform a dry-run in order to proceed. Always check dry-run results."
+)]
+pub struct RunArgs {
+    /// Action to perform
+    #[arg(value_enum)]
+    pub action: RunAction,
+    /// Also retire outdated kernel packages
+    #[arg(short = 'k', long, default_value_t = false)]
+    pub kernel: bool,
+    /// Also retire out-of-tree packages
+    #[arg(short = 'o', long, default_value_t = false)]
+    pub out_of_tree: bool,
+    /// Also retire the topic repositories merged into stable
+    #[arg(long, default_value_t = false)]
+    pub topics: bool,
+    /// Keep the -dbg packages left without their base packages, retired by default
+    #[arg(long, default_value_t = false)]
+    pub no_orphaned_dbg: bool,
+    /// Refuse to retire unless the plan saved by the dry-run has this checksum
+    #[arg(long)]
+    pub expect_sha256: Option<String>,
+    /// Specify which ABBS tree to use, defaults to ~/aosc-os-abbs
+    #[arg(short = 'd', long, env = "ABBS_DIR")]
+    pub abbs_dir: Option<String>,
+    /// Specify the revision (appended to the date)
+    #[arg(short = 'r', long)]
+    pub revision: Option<String>,
+    /// Prefix of the output directories
+    #[arg(long, default_value = "/lookaside/public/archives")]
+    pub prefix: String,
+    /// Path to the p-vector config file
+    #[arg(short = 'c', long, default_value = "/etc/p-vector/aosc-os.toml")]
+    pub config: String,
+    /// Never retire the packages on the protect-list in this TOML file
+    #[arg(long)]
+    pub protect: Option<String>,
+    /// Leave out the default protect-list entries
+    #[arg(long, default_value_t = false)]
+    pub no_default_protect: bool,
 }
 
 #[derive(Parser)]
@@ -55,4 +260,19 @@ pub enum Args {
     Retire(RetireArgs),
     /// Slice the directory into fixed-sized chunks
     Binning(BinningArgs),
+    /// Create a Blu-ray disc image from a volume
+    #[command(name = "mkimage")]
+    MkImage(MkImageArgs),
+    /// Import the manifests of existing discs into the catalog
+    ImportDiscs(ImportDiscsArgs),
+    /// Find the archived versions of a package and the discs holding them, failing if there are none
+    Search(SearchArgs),
+    /// Find the files stored on more than one disc, and plan their removal
+    DedupReport(DedupReportArgs),
+    /// Return a retired package to the repository pool
+    Restore(RestoreArgs),
+    /// Put every package of a retirement batch back in the repository pool
+    Rollback(RollbackArgs),
+    /// Retire packages as the operator, with the checks of the retirement workflow
+    Run(RunArgs),
 }
@@ -1,13 +1,20 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 
 use anyhow::{Context, Result};
-use log::debug;
-use rusqlite::{params, Connection};
-use sqlx::{query, query_as, PgPool};
+use log::{debug, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, PgPool};
+
+use crate::kernel::{KernelPolicy, RetiredKernels};
+use crate::retention::Retention;
+use crate::version::{compare_versions, register_sqlite};
 
 const SQLITE_INIT_SCRIPT: &str = include_str!("../init.sql");
+const CATALOG_INIT_SCRIPT: &str = include_str!("../catalog.sql");
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PackageMeta {
     pub package: String,
     pub sha256: String,
@@ -18,126 +25,429 @@ pub struct PackageMeta {
     pub repo: String,
 }
 
-pub async fn determine_retired_packages(pool: &PgPool, oot: bool) -> Result<Vec<PackageMeta>> {
-    let packages = query_as!(
-        PackageMeta,
-        r#"SELECT package, sha256, size, filename, version, architecture, repo FROM 
-(SELECT *, rank() OVER (PARTITION BY package, repo ORDER BY _vercomp DESC) AS pos FROM pv_packages) 
-AS sq WHERE pos > 1"#
+/// Why a package is retired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    /// A newer version is in the same repository
+    Superseded,
+    /// The package is no longer in the ABBS tree
+    OutOfTree,
+    /// No longer kept by the kernel policy
+    OutdatedKernel,
+    /// Built for a kernel version which is retired
+    KernelCompanion,
+    /// The debug symbols of a package version which is gone
+    OrphanedDebug,
+    /// The topic repository has been merged into stable
+    MergedTopic,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Superseded => "superseded",
+            Self::OutOfTree => "out-of-tree",
+            Self::OutdatedKernel => "outdated-kernel",
+            Self::KernelCompanion => "kernel-companion",
+            Self::OrphanedDebug => "orphaned-debug",
+            Self::MergedTopic => "merged-topic",
+        })
+    }
+}
+
+/// A package in the repository, with what is needed to rank its versions.
+struct PoolPackage {
+    package: String,
+    sha256: String,
+    size: i64,
+    filename: String,
+    version: String,
+    architecture: String,
+    repo: String,
+    mtime: i32,
+    /// The comparable form of the version made by p-vector
+    vercomp: String,
+}
+
+/// A package version, and its position among the versions of the package in
+/// its repository, the newest being 1.
+struct RankedPackage {
+    meta: PackageMeta,
+    pos: usize,
+    /// When the next newer version entered the pool
+    superseded_at: Option<i32>,
+}
+
+/// Ranks the versions of each package in its repository, warning where
+/// p-vector orders them differently.
+fn rank_packages(packages: Vec<PoolPackage>) -> Vec<RankedPackage> {
+    let mut groups: BTreeMap<(String, String), Vec<PoolPackage>> = BTreeMap::new();
+    for p in packages {
+        groups
+            .entry((p.package.clone(), p.repo.clone()))
+            .or_default()
+            .push(p);
+    }
+    let mut ranked = Vec::new();
+    for (_, mut versions) in groups {
+        versions.sort_by(|a, b| compare_versions(&b.version, &a.version));
+        for pair in versions.windows(2) {
+            let (newer, older) = (&pair[0], &pair[1]);
+            let order = compare_versions(&newer.version, &older.version);
+            if newer.vercomp.cmp(&older.vercomp) != order {
+                warn!(
+                    "p-vector orders {} {} and {} differently in {}",
+                    newer.package, newer.version, older.version, newer.repo
+                );
+            }
+        }
+        let mut pos = 0;
+        let mut superseded_at = None;
+        for (i, p) in versions.iter().enumerate() {
+            // equal versions share their position
+            if i == 0 || compare_versions(&versions[i - 1].version, &p.version).is_ne() {
+                pos = i + 1;
+                superseded_at = (i > 0).then(|| versions[i - 1].mtime);
+            }
+            ranked.push(RankedPackage {
+                meta: PackageMeta {
+                    package: p.package.clone(),
+                    sha256: p.sha256.clone(),
+                    size: p.size,
+                    filename: p.filename.clone(),
+                    version: p.version.clone(),
+                    architecture: p.architecture.clone(),
+                    repo: p.repo.clone(),
+                },
+                pos,
+                superseded_at,
+            });
+        }
+    }
+
+    ranked
+}
+
+/// The packages to retire, and the candidates kept for a while or still
+/// depended on, along with why they are kept.
+#[derive(Debug, Default)]
+pub struct RetiredPackages {
+    pub packages: Vec<(PackageMeta, Reason)>,
+    pub kept: Vec<(PackageMeta, String)>,
+}
+
+pub async fn determine_retired_packages(
+    pool: &PgPool,
+    oot: bool,
+    retention: &Retention,
+) -> Result<RetiredPackages> {
+    let pool_packages = query_as!(
+        PoolPackage,
+        r#"SELECT package, sha256, size, filename, version, architecture, repo, mtime,
+_vercomp AS vercomp FROM pv_packages"#
     )
     .fetch_all(pool)
     .await?;
+    let now = chrono::Utc::now().timestamp();
+    let mut retired = RetiredPackages::default();
+    for p in rank_packages(pool_packages) {
+        if p.pos <= retention.rule(&p.meta.package, &p.meta.repo).keep() {
+            continue;
+        }
+        let kept = p
+            .superseded_at
+            .and_then(|t| retention.keep_recent(t.into(), now));
+        match kept {
+            Some(why) => retired.kept.push((p.meta, why)),
+            None => retired.packages.push((p.meta, Reason::Superseded)),
+        }
+    }
 
     if oot {
-        let mut oot_packages = query_as!(
+        let oot_packages = query_as!(
             PackageMeta,
         r#"SELECT DISTINCT pp.package, pp.sha256, pp.size, pp.filename, pp.version, pp.architecture, pp.repo FROM 
 pv_packages pp LEFT JOIN packages p ON pp.package = p.name WHERE 
-tree IS NULL AND pp.package NOT LIKE '%-dbg' AND pp.package NOT SIMILAR TO '(linux-kernel-|linux\+kernel\+|u-boot)%'
-AND pp.repo LIKE '%/stable'"#).fetch_all(pool).await?;
-        oot_packages.extend(packages);
-        return Ok(oot_packages);
+tree IS NULL AND pp.repo LIKE '%/stable'"#).fetch_all(pool).await?;
+        // the out-of-tree packages come first, as they are retired for that
+        retired.packages.splice(
+            0..0,
+            oot_packages.into_iter().map(|p| (p, Reason::OutOfTree)),
+        );
     }
 
+    Ok(retired)
+}
+
+/// A package version in the repository, enough to resolve dependencies.
+#[derive(Debug, Clone)]
+pub struct PoolVersion {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: String,
+    pub filename: String,
+}
+
+/// The `Depends` or `Pre-Depends` field of a package version.
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: String,
+    pub filename: String,
+    pub value: String,
+}
+
+/// Lists every package version in the repository, to resolve dependencies
+/// against.
+pub async fn find_pool_versions(pool: &PgPool) -> Result<Vec<PoolVersion>> {
+    let versions = query_as!(
+        PoolVersion,
+        r#"SELECT package, version, architecture, repo, filename FROM pv_packages"#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(versions)
+}
+
+/// Finds the dependencies with an exact version or an upper bound, which
+/// an older version may be the only one to satisfy.
+pub async fn find_bounded_dependencies(pool: &PgPool) -> Result<Vec<Dependency>> {
+    let dependencies = query_as!(
+        Dependency,
+        r#"SELECT d.package, d.version, p.architecture, d.repo, p.filename, d.value
+FROM pv_package_dependencies d JOIN pv_packages p USING (package, version, repo)
+WHERE d.relationship IN ('Depends', 'Pre-Depends') AND d.value ~ '\(\s*(=|<)'"#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(dependencies)
+}
+
+/// Finds the -dbg packages whose base package of the same version is no
+/// longer in the repository.
+pub async fn determine_orphaned_dbg_packages(pool: &PgPool) -> Result<Vec<PackageMeta>> {
+    let packages = query_as!(
+        PackageMeta,
+        r#"SELECT d.package, d.sha256, d.size, d.filename, d.version, d.architecture, d.repo
+FROM pv_packages d WHERE d.package LIKE '%-dbg' AND NOT EXISTS (
+    SELECT 1 FROM pv_packages b
+    WHERE b.package = substring(d.package FROM '^(.*)-dbg$')
+    AND b.version = d.version AND b.repo = d.repo
+)"#
+    )
+    .fetch_all(pool)
+    .await?;
+
     Ok(packages)
 }
 
-pub async fn determine_retired_kernel_packages(pool: &PgPool) -> Result<Vec<PackageMeta>> {
-    // Sorry, but I think the easiest way to do it is to use subqueries.
-    // Feel free to improve the following query.
-    log::info!("Fecthing packages to retire for the mainline kernel ...");
-    let mut collection = query_as!(
+/// A topic repository, and how many of its packages are not in stable with
+/// the same or a newer version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicStatus {
+    pub repo: String,
+    pub packages: i64,
+    pub unmerged: i64,
+}
+
+/// A package version in a topic repository, or in the stable repository of
+/// the same architecture.
+struct TopicVersion {
+    repo: String,
+    branch: String,
+    architecture: String,
+    package: String,
+    version: String,
+}
+
+/// Counts the packages of each topic repository, and those of them not in
+/// stable with the same or a newer version.
+fn topic_statuses(versions: Vec<TopicVersion>) -> Vec<TopicStatus> {
+    let (stable, topics): (Vec<_>, Vec<_>) =
+        versions.into_iter().partition(|v| v.branch == "stable");
+    let mut newest: HashMap<(&str, &str), &str> = HashMap::new();
+    for v in stable.iter() {
+        let version = newest
+            .entry((&v.architecture, &v.package))
+            .or_insert(&v.version);
+        if compare_versions(&v.version, version).is_gt() {
+            *version = &v.version;
+        }
+    }
+    let mut statuses: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+    for v in topics.iter() {
+        let merged = newest
+            .get(&(v.architecture.as_str(), v.package.as_str()))
+            .is_some_and(|stable| compare_versions(stable, &v.version).is_ge());
+        let status = statuses.entry(&v.repo).or_default();
+        status.0 += 1;
+        status.1 += i64::from(!merged);
+    }
+
+    statuses
+        .into_iter()
+        .map(|(repo, (packages, unmerged))| TopicStatus {
+            repo: repo.to_owned(),
+            packages,
+            unmerged,
+        })
+        .collect()
+}
+
+/// Finds the repositories of the topic branches, comparing their packages to
+/// the stable repository of the same architecture.
+pub async fn find_topics(pool: &PgPool) -> Result<Vec<TopicStatus>> {
+    let versions = query_as!(
+        TopicVersion,
+        r#"SELECT r.name AS "repo!", r.branch AS "branch!", r.architecture AS "architecture!",
+p.package, p.version
+FROM pv_packages p JOIN pv_repos r ON p.repo = r.name
+WHERE r.branch <> 'stable' OR EXISTS (
+    SELECT 1 FROM pv_packages t JOIN pv_repos tr ON t.repo = tr.name
+    WHERE tr.branch <> 'stable' AND tr.architecture = r.architecture
+    AND t.package = p.package
+)"#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(topic_statuses(versions))
+}
+
+/// Finds every package in these repositories.
+pub async fn find_packages_in_repos(pool: &PgPool, repos: &[String]) -> Result<Vec<PackageMeta>> {
+    let packages = query_as!(
         PackageMeta,
-        r#"
-        SELECT DISTINCT
-            p1.package, p1.sha256, p1.size, p1.filename, p1.version, p1.architecture, p1.repo
-        FROM pv_packages p1
-        WHERE
-	        p1.package LIKE 'linux-kernel-%'
-	        AND p1.package SIMILAR TO 'linux-kernel-[0-9]%'
-	        AND NOT EXISTS (
-                SELECT 1
-                FROM pv_packages p2
-                WHERE
-                    p2.package = 'linux+kernel'
-                    AND substring(p1.version FROM '[0-9]+\.[0-9]+\.[0-9]+') = substring(p2.version FROM '[0-9]+\.[0-9]+\.[0-9]+')
-            )
-    "#
+        r#"SELECT package, sha256, size, filename, version, architecture, repo FROM pv_packages
+WHERE repo = ANY($1)"#,
+        repos
     )
     .fetch_all(pool)
     .await?;
-    // WARNING: we can not hardcode kernel variants.
-    let other_variants = query!(
-        r#"
-        SELECT DISTINCT
-            substring(package FROM '(?:linux\+kernel\+)(.*)')
-            AS variants
-        FROM pv_packages
-        WHERE
-            package SIMILAR TO 'linux\+kernel\+%';
-    "#
+
+    Ok(packages)
+}
+
+/// Finds the packages with these file names in the repository.
+pub async fn find_packages_by_filename(
+    pool: &PgPool,
+    filenames: &[String],
+) -> Result<Vec<PackageMeta>> {
+    let packages = query_as!(
+        PackageMeta,
+        r#"SELECT package, sha256, size, filename, version, architecture, repo FROM pv_packages
+WHERE filename = ANY($1)"#,
+        filenames
     )
     .fetch_all(pool)
     .await?;
-    // NOTE: I think it must be done this way, otherwise we have to alter
-    // the database (to create a function that can return rows) and call
-    // that function in this series of queries.
-    // Also due to the naming convention of kernel packages, I can't come
-    // up a better idea that the query can be done without loops. Sorry,
-    // but this is the best thing I can do for now.
-    for variant in other_variants {
-        let variant_name = variant.variants.context("Got an empty variant name")?;
-        log::info!(
-            "Fecthing packages to retire for kernel variant {}",
-            &variant_name
-        );
-        // Let's log available versions for linux+kernel metapackages, just
-        // to make sure.
-        let available_versions = query!(
-            r#"
-            SELECT DISTINCT
-                substring(version FROM '[0-9]+\.[0-9]+\.[0-9]+')
-                AS version
-            FROM pv_packages
-            WHERE
-                package = ('linux+kernel+' || $1)
-        "#,
-            &variant_name
-        )
-        .fetch_all(pool)
-        .await?;
-        log::info!("Available versions for 'linux+kernel+{}' :", &variant_name);
-        available_versions
-            .iter()
-            .for_each(|x| log::info!(" - {}", x.version.clone().unwrap_or_default()));
-        let collection_other_variants = query_as!(
+
+    Ok(packages)
+}
+
+/// Decides which kernel packages to retire with the policy, given the
+/// metapackages of the variants in the repository, along with the packages
+/// built for them.
+pub async fn determine_retired_kernel_packages(
+    pool: &PgPool,
+    policy: &KernelPolicy,
+) -> Result<RetiredKernels> {
+    log::info!("Fetching the kernel packages and their metapackages ...");
+    let packages = query_as!(
+        PackageMeta,
+        r#"SELECT package, sha256, size, filename, version, architecture, repo FROM pv_packages
+WHERE package LIKE 'linux-kernel-%' OR package LIKE 'linux+kernel%'"#
+    )
+    .fetch_all(pool)
+    .await?;
+    let (metapackages, kernels): (Vec<_>, Vec<_>) = packages
+        .into_iter()
+        .partition(|p| p.package.starts_with("linux+kernel"));
+
+    let mut retired = policy.select(kernels, &metapackages);
+    let versions = retired.retired_versions();
+    if !versions.is_empty() {
+        log::info!("Looking for packages built for the retired kernels ...");
+        let packages = query_as!(
             PackageMeta,
-            r#"
-            SELECT DISTINCT
-                p1.package, p1.sha256, p1.size, p1.filename, p1.version, p1.architecture, p1.repo
-            FROM pv_packages p1
-            WHERE
-                p1.package SIMILAR TO ('linux-kernel-' || $1 || '-[0-9]%')
-                AND NOT EXISTS (
-                    SELECT 1
-                    FROM pv_packages p2
-                    WHERE
-                        p2.package = ('linux+kernel+' || $1)
-                        AND substring(p1.version FROM '[0-9]+\.[0-9]+\.[0-9]+') = substring(p2.version FROM '[0-9]+\.[0-9]+\.[0-9]+')
-                )
-        "#,
-            variant_name
+            r#"SELECT package, sha256, size, filename, version, architecture, repo FROM pv_packages
+WHERE EXISTS (
+    SELECT 1 FROM unnest($1::text[]) v
+    WHERE strpos(package, v) > 0 OR strpos(version, v) > 0
+)"#,
+            &versions
         )
         .fetch_all(pool)
         .await?;
-        collection.extend(collection_other_variants);
+        policy.add_companions(&mut retired, packages)?;
     }
-    Ok(collection)
+
+    Ok(retired)
 }
 
-pub fn save_new_packages<P: AsRef<Path>>(db_path: P, packages: &[PackageMeta]) -> Result<()> {
-    let mut conn = Connection::open(db_path)?;
+/// Opens an archive database, with the Debian version comparison available
+/// to its queries.
+fn open_db<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
+    let conn = Connection::open(db_path)?;
+    register_sqlite(&conn)?;
+
+    Ok(conn)
+}
+
+fn labels_columns(conn: &Connection) -> Result<HashSet<String>> {
+    let columns = conn
+        .prepare("SELECT name FROM pragma_table_info('packages')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(columns)
+}
+
+/// Opens the archive database, creating the tables or adding the columns
+/// missing from databases created by earlier versions.
+fn open_labels_db<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
+    let conn = open_db(db_path)?;
     conn.execute_batch(SQLITE_INIT_SCRIPT)?;
+    let columns = labels_columns(&conn)?;
+    if !columns.contains("status") {
+        conn.execute_batch(
+            "ALTER TABLE packages ADD COLUMN status TEXT NOT NULL DEFAULT 'archived'",
+        )?;
+    }
+    if !columns.contains("location") {
+        conn.execute_batch("ALTER TABLE packages ADD COLUMN location TEXT")?;
+    }
+    // databases created by earlier versions are keyed by the checksum alone
+    let keyed_by_filename: bool = conn.query_row(
+        "SELECT pk > 0 FROM pragma_table_info('packages') WHERE name = 'filename'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !keyed_by_filename {
+        conn.execute_batch(&format!(
+            "BEGIN;
+DROP INDEX package_version;
+ALTER TABLE packages RENAME TO packages_old;
+{}
+INSERT INTO packages SELECT package, sha256, size, architecture, filename, version, repo,
+retire_date, status, location FROM packages_old;
+DROP TABLE packages_old;
+COMMIT;",
+            SQLITE_INIT_SCRIPT
+        ))?;
+    }
+
+    Ok(conn)
+}
+
+pub fn save_new_packages<P: AsRef<Path>>(db_path: P, packages: &[PackageMeta]) -> Result<()> {
+    let mut conn = open_labels_db(db_path)?;
     let tx = conn.transaction()?;
 
     for p in packages {
@@ -150,12 +460,319 @@ pub fn save_new_packages<P: AsRef<Path>>(db_path: P, packages: &[PackageMeta]) -
     Ok(())
 }
 
+/// Records the packages that were found in earlier batches or on the discs,
+/// along with where their existing copies live.
+pub fn save_archived_packages<P: AsRef<Path>>(
+    db_path: P,
+    packages: &[(PackageMeta, String)],
+) -> Result<()> {
+    let mut conn = open_labels_db(db_path)?;
+    let tx = conn.transaction()?;
+
+    for (p, location) in packages {
+        tx.execute("INSERT INTO packages (package, sha256, size, filename, version, architecture, repo, status, location) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'already-archived', ?8)", params![p.package, p.sha256, p.size, p.filename, p.version, p.architecture, p.repo, location]).context(format!("when processing {}", p.filename))?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Returns a map of the checksums of the packages archived in this batch to
+/// their file names. Packages that were already archived elsewhere or rolled
+/// back are left out, restored packages are not since their archived copies
+/// are kept.
+pub fn load_archived_checksums<P: AsRef<Path>>(db_path: P) -> Result<HashMap<String, String>> {
+    let conn = open_db(db_path)?;
+    // databases created by earlier versions only have archived packages
+    let mut stmt = if labels_columns(&conn)?.contains("status") {
+        conn.prepare(
+            "SELECT sha256, filename FROM packages WHERE status IN ('archived', 'restored')",
+        )?
+    } else {
+        conn.prepare("SELECT sha256, filename FROM packages")?
+    };
+    let checksums = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(checksums)
+}
+
+/// A file of a package in an archive database.
+#[derive(Debug, Clone)]
+pub struct ArchivedFile {
+    pub filename: String,
+    pub sha256: String,
+    pub status: String,
+    /// Where the existing copy lives, for already-archived packages
+    pub location: Option<String>,
+}
+
+/// Finds the files of the version of the package in the archive database.
+pub fn find_archived_files<P: AsRef<Path>>(
+    db_path: P,
+    package: &str,
+    version: &str,
+    arch: Option<&str>,
+) -> Result<Vec<ArchivedFile>> {
+    let conn = open_db(db_path)?;
+    // databases created by earlier versions only have archived packages
+    let columns = if labels_columns(&conn)?.contains("status") {
+        "status, location"
+    } else {
+        "'archived', NULL"
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT filename, sha256, {} FROM packages
+WHERE package = ?1 AND version = ?2 AND (?3 IS NULL OR architecture = ?3)",
+        columns
+    ))?;
+    let files = stmt
+        .query_map(params![package, version, arch], |row| {
+            Ok(ArchivedFile {
+                filename: row.get(0)?,
+                sha256: row.get(1)?,
+                status: row.get(2)?,
+                location: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(files)
+}
+
+/// Marks the package file as restored to the pool.
+pub fn mark_restored<P: AsRef<Path>>(db_path: P, filename: &str) -> Result<()> {
+    let conn = open_labels_db(db_path)?;
+    conn.execute(
+        "UPDATE packages SET status = 'restored' WHERE filename = ?1 AND status = 'archived'",
+        params![filename],
+    )?;
+
+    Ok(())
+}
+
+/// Returns whether the package file is recorded in the archive database.
+pub fn is_recorded<P: AsRef<Path>>(db_path: P, filename: &str) -> Result<bool> {
+    if !db_path.as_ref().exists() {
+        return Ok(false);
+    }
+    let conn = open_db(db_path)?;
+    let found = conn
+        .query_row(
+            "SELECT 1 FROM packages WHERE filename = ?1",
+            params![filename],
+            |_| Ok(()),
+        )
+        .optional()?;
+
+    Ok(found.is_some())
+}
+
+/// Marks the package file as put back into the pool by a rollback.
+pub fn mark_rolled_back<P: AsRef<Path>>(db_path: P, filename: &str) -> Result<()> {
+    let conn = open_labels_db(db_path)?;
+    conn.execute(
+        "UPDATE packages SET status = 'rolled-back' WHERE filename = ?1",
+        params![filename],
+    )?;
+
+    Ok(())
+}
+
+/// A package in an archive database, with its status.
+#[derive(Debug, Clone)]
+pub struct BatchPackage {
+    pub package: PackageMeta,
+    pub status: String,
+    pub location: Option<String>,
+}
+
+/// Returns all the packages of the batch.
+pub fn load_batch_packages<P: AsRef<Path>>(db_path: P) -> Result<Vec<BatchPackage>> {
+    let conn = open_labels_db(db_path)?;
+    let mut stmt = conn.prepare("SELECT package, sha256, size, filename, version, architecture, repo, status, location FROM packages ORDER BY filename")?;
+    let packages = stmt
+        .query_map([], |row| {
+            Ok(BatchPackage {
+                package: PackageMeta {
+                    package: row.get(0)?,
+                    sha256: row.get(1)?,
+                    size: row.get(2)?,
+                    filename: row.get(3)?,
+                    version: row.get(4)?,
+                    architecture: row.get(5)?,
+                    repo: row.get(6)?,
+                },
+                status: row.get(7)?,
+                location: row.get(8)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(packages)
+}
+
+/// Records an event affecting the whole batch, e.g. a rollback.
+pub fn record_history<P: AsRef<Path>>(
+    db_path: P,
+    event: &str,
+    packages: usize,
+    missing: usize,
+) -> Result<()> {
+    let conn = open_labels_db(db_path)?;
+    conn.execute(
+        "INSERT INTO history (event, packages, missing) VALUES (?1, ?2, ?3)",
+        params![event, packages as i64, missing as i64],
+    )?;
+
+    Ok(())
+}
+
+/// Returns a map of file names to package names recorded in the archive database.
+pub fn load_package_names<P: AsRef<Path>>(db_path: P) -> Result<HashMap<String, String>> {
+    let conn = open_db(db_path)?;
+    let mut stmt = conn.prepare("SELECT filename, package FROM packages")?;
+    let names = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(names)
+}
+
+/// Returns a map of file names to sizes recorded in the archive database.
+pub fn load_package_sizes<P: AsRef<Path>>(db_path: P) -> Result<HashMap<String, i64>> {
+    let conn = open_db(db_path)?;
+    let mut stmt = conn.prepare("SELECT filename, size FROM packages")?;
+    let sizes = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(sizes)
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscEntry {
+    pub project: String,
+    pub path: String,
+    pub md5: String,
+}
+
+/// Replaces the entries of the disc in the catalog.
+pub fn save_disc_entries<P: AsRef<Path>>(
+    db_path: P,
+    disc: i64,
+    entries: &[DiscEntry],
+) -> Result<()> {
+    let mut conn = open_db(db_path)?;
+    conn.execute_batch(CATALOG_INIT_SCRIPT)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM discs WHERE disc = ?1", params![disc])?;
+
+    for e in entries {
+        tx.execute(
+            "INSERT INTO discs (disc, project, path, md5) VALUES (?1, ?2, ?3, ?4)",
+            params![disc, e.project, e.path, e.md5],
+        )
+        .context(format!("when processing {}", e.path))?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Returns all the entries in the catalog, as pairs of disc numbers and entries.
+pub fn load_disc_entries<P: AsRef<Path>>(db_path: P) -> Result<Vec<(i64, DiscEntry)>> {
+    let conn = open_db(db_path)?;
+    conn.execute_batch(CATALOG_INIT_SCRIPT)?;
+    let mut stmt = conn.prepare("SELECT disc, project, path, md5 FROM discs")?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                DiscEntry {
+                    project: row.get(1)?,
+                    path: row.get(2)?,
+                    md5: row.get(3)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(entries)
+}
+
+/// A package recorded in an archive database.
+#[derive(Debug, Clone)]
+pub struct ArchivedPackage {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: String,
+    pub filename: String,
+    pub retire_date: String,
+}
+
+/// Finds the packages matching the name (a glob pattern), and optionally
+/// the version and architecture, in the archive database.
+pub fn search_archived_packages<P: AsRef<Path>>(
+    db_path: P,
+    name: &str,
+    version: Option<&str>,
+    arch: Option<&str>,
+) -> Result<Vec<ArchivedPackage>> {
+    let conn = open_db(db_path)?;
+    // rolled back packages are not in the archive
+    let rolled_back = if labels_columns(&conn)?.contains("status") {
+        "AND status != 'rolled-back'"
+    } else {
+        ""
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT package, version, architecture, repo, filename, retire_date FROM packages
+WHERE package GLOB ?1 AND (?2 IS NULL OR version = ?2) AND (?3 IS NULL OR architecture = ?3) {}
+ORDER BY package, version COLLATE debversion, architecture",
+        rolled_back
+    ))?;
+    let packages = stmt
+        .query_map(params![name, version, arch], |row| {
+            Ok(ArchivedPackage {
+                package: row.get(0)?,
+                version: row.get(1)?,
+                architecture: row.get(2)?,
+                repo: row.get(3)?,
+                filename: row.get(4)?,
+                retire_date: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(packages)
+}
+
+/// Finds the Debian packages whose name matches the glob pattern in the
+/// catalog, as pairs of disc numbers and paths.
+pub fn search_disc_entries<P: AsRef<Path>>(db_path: P, name: &str) -> Result<Vec<(i64, String)>> {
+    let conn = open_db(db_path)?;
+    conn.execute_batch(CATALOG_INIT_SCRIPT)?;
+    let mut stmt =
+        conn.prepare("SELECT disc, path FROM discs WHERE path GLOB ('*/' || ?1 || '_*.deb')")?;
+    let entries = stmt
+        .query_map(params![name], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(entries)
+}
+
 #[tokio::test]
 async fn test_kernel_packages_to_retire() -> Result<()> {
     use bytesize::ByteSize;
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
+        .try_init()
+        .ok();
     let db_url = if let Ok(v) = std::env::var("DB_URL") {
         v
     } else {
@@ -163,7 +780,10 @@ async fn test_kernel_packages_to_retire() -> Result<()> {
     };
 
     let pool = sqlx::PgPool::connect(&db_url).await?;
-    let collection = determine_retired_kernel_packages(&pool).await?;
+    let policy = KernelPolicy::new(Default::default())?;
+    let collection = determine_retired_kernel_packages(&pool, &policy)
+        .await?
+        .packages;
     let mut total_retired_size: u64 = 0;
     for package in &collection {
         log::info!(
@@ -180,3 +800,135 @@ async fn test_kernel_packages_to_retire() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn test_rank_packages() {
+    let package = |version: &str, vercomp: &str, mtime: i32| PoolPackage {
+        package: "glibc".to_owned(),
+        sha256: String::new(),
+        size: 0,
+        filename: format!("pool/stable/main/g/glibc_{}_amd64.deb", version),
+        version: version.to_owned(),
+        architecture: "amd64".to_owned(),
+        repo: "amd64/stable".to_owned(),
+        mtime,
+        vercomp: vercomp.to_owned(),
+    };
+    let ranked = rank_packages(vec![
+        package("2.36-1", "a", 1),
+        package("1:2.30-0", "d", 4),
+        package("2.36~rc1-0", "b", 2),
+        package("2.40-0", "c", 3),
+    ]);
+    let ranked = ranked
+        .iter()
+        .map(|p| (p.meta.version.as_str(), p.pos, p.superseded_at))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ranked,
+        vec![
+            ("1:2.30-0", 1, None),
+            ("2.40-0", 2, Some(4)),
+            ("2.36-1", 3, Some(3)),
+            ("2.36~rc1-0", 4, Some(1)),
+        ]
+    );
+}
+
+#[test]
+fn test_topic_statuses() {
+    let version = |repo: &str, package: &str, version: &str| {
+        let (architecture, branch) = repo.split_once('/').unwrap();
+        TopicVersion {
+            repo: repo.to_owned(),
+            branch: branch.to_owned(),
+            architecture: architecture.to_owned(),
+            package: package.to_owned(),
+            version: version.to_owned(),
+        }
+    };
+    let topics = topic_statuses(vec![
+        version("amd64/stable", "glibc", "2.40-0"),
+        version("amd64/stable", "glibc", "2.36-1"),
+        version("amd64/stable", "gcc", "13.2.0"),
+        version("arm64/stable", "gcc", "14.1.0"),
+        // merged, stable having a newer version
+        version("amd64/glibc-2.38", "glibc", "2.38-0"),
+        // not merged, the epoch making it newer
+        version("amd64/gcc-14", "gcc", "1:12.0"),
+        version("amd64/gcc-14", "libgcc", "14.1.0"),
+        version("amd64/gcc-14", "glibc", "2.40~rc1-0"),
+    ]);
+    assert_eq!(
+        topics,
+        vec![
+            TopicStatus {
+                repo: "amd64/gcc-14".to_owned(),
+                packages: 3,
+                unmerged: 2,
+            },
+            TopicStatus {
+                repo: "amd64/glibc-2.38".to_owned(),
+                packages: 1,
+                unmerged: 0,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_labels_db() -> Result<()> {
+    let root = crate::testing::TempDir::new("labels")?;
+    let db_path = root.join("labels-20240101.db");
+    // as created by earlier versions
+    Connection::open(&db_path)?.execute_batch(
+        "CREATE TABLE packages (
+    package TEXT NOT NULL,
+    sha256 TEXT NOT NULL PRIMARY KEY,
+    size INTEGER NOT NULL,
+    architecture TEXT NOT NULL,
+    filename TEXT NOT NULL UNIQUE,
+    version TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    retire_date DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE UNIQUE INDEX package_version ON packages (package, version, architecture, repo, sha256);
+INSERT INTO packages (package, sha256, size, architecture, filename, version, repo)
+VALUES ('wget', 'wget', 1, 'amd64', 'pool/stable/main/w/wget_1.0_amd64.deb', '1.0', 'amd64/stable');",
+    )?;
+    // the same file in a topic and in stable
+    let stable = PackageMeta {
+        sha256: "webkit2gtk".to_owned(),
+        ..crate::testing::package("webkit2gtk", "2.20.0-0", "amd64")
+    };
+    let topic = PackageMeta {
+        filename: "pool/webkit/main/w/webkit2gtk_2.20.0-0_amd64.deb".to_owned(),
+        repo: "amd64/webkit".to_owned(),
+        ..stable.clone()
+    };
+    let saved = save_new_packages(&db_path, &[stable.clone(), topic.clone()]);
+    let rolled_back = mark_rolled_back(&db_path, &topic.filename);
+    let packages = load_batch_packages(&db_path)?;
+    drop(root);
+    saved?;
+    rolled_back?;
+    let packages = packages
+        .iter()
+        .map(|p| (p.package.filename.as_str(), p.status.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        packages,
+        vec![
+            (
+                "pool/stable/main/w/webkit2gtk_2.20.0-0_amd64.deb",
+                "archived"
+            ),
+            ("pool/stable/main/w/wget_1.0_amd64.deb", "archived"),
+            (
+                "pool/webkit/main/w/webkit2gtk_2.20.0-0_amd64.deb",
+                "rolled-back"
+            ),
+        ]
+    );
+    Ok(())
+}
@@ -19,6 +19,8 @@ pub struct ServiceState<'a> {
 )]
 trait SystemdManager {
     fn get_unit(&self, name: &str) -> zbus::Result<zbus::zvariant::OwnedObjectPath>;
+
+    fn load_unit(&self, name: &str) -> zbus::Result<zbus::zvariant::OwnedObjectPath>;
 }
 
 #[zbus::dbus_proxy(interface = "org.freedesktop.systemd1.Unit", assume_defaults = true)]
@@ -27,6 +29,9 @@ trait SystemdUnit {
 
     fn stop(&self, mode: &str) -> zbus::Result<zbus::zvariant::OwnedObjectPath>;
 
+    #[dbus_proxy(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+
     #[dbus_proxy(property)]
     fn sub_state(&self) -> zbus::Result<String>;
 
@@ -51,7 +56,10 @@ async fn get_services<S: AsRef<str>>(
     Ok(results)
 }
 
-async fn get_service_status(conn: &Connection, service: OwnedObjectPath) -> Result<ServiceState> {
+async fn get_service_status(
+    conn: &Connection,
+    service: OwnedObjectPath,
+) -> Result<ServiceState<'_>> {
     let proxy = SystemdUnitProxy::builder(conn)
         .path(service)?
         .build()
@@ -119,6 +127,26 @@ pub async fn inhibit_services<'a, S: AsRef<str>>(
     Ok(active_triggers)
 }
 
+/// Finds the units which are active, activating or reloading. Units that
+/// do not exist are inactive.
+pub async fn find_active_units<S: AsRef<str>>(
+    conn: &Connection,
+    units: &[S],
+) -> Result<Vec<String>> {
+    let proxy = SystemdManagerProxy::new(conn).await?;
+    let mut active = Vec::new();
+    for unit in units.iter() {
+        let path = proxy.load_unit(unit.as_ref()).await?;
+        let unit_proxy = SystemdUnitProxy::builder(conn).path(path)?.build().await?;
+        let state = unit_proxy.active_state().await?;
+        if state != "inactive" && state != "failed" {
+            active.push(unit.as_ref().to_owned());
+        }
+    }
+
+    Ok(active)
+}
+
 pub async fn restore_services(services: &[ServiceState<'_>]) -> Result<()> {
     for service in services {
         let proxy = &service.proxy;
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytesize::ByteSize;
+use log::info;
+use serde::Serialize;
+
+use crate::binning::volume_name;
+use crate::catalog::{find_label_dbs, list_discs};
+use crate::db::{load_disc_entries, load_package_sizes};
+use crate::manifest::{md5_name, parse_md5};
+
+/// Checksum of empty files, which take no space and are not duplicates of
+/// each other in any useful sense.
+const EMPTY_MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+/// A file on a disc, with its path relative to the root of the disc.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct DiscCopy {
+    pub disc: i64,
+    pub path: String,
+}
+
+/// A file stored on more than one disc. The copy on the disc with the
+/// lowest number is kept, the others can be removed when remastering.
+#[derive(Debug, Serialize)]
+pub struct Duplicate {
+    pub md5: String,
+    pub size: Option<u64>,
+    pub keep: DiscCopy,
+    pub remove: Vec<DiscCopy>,
+}
+
+/// Files with the same path but different contents on different discs.
+#[derive(Debug, Serialize)]
+pub struct Conflict {
+    /// Path without the project directory
+    pub path: String,
+    pub copies: Vec<(DiscCopy, String)>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DiscSummary {
+    pub disc: i64,
+    /// Number of files that can be removed from the disc
+    pub files: usize,
+    /// Bytes taken by the files that can be removed, if their size is known
+    pub reclaimable: u64,
+    /// Number of files whose size is unknown
+    pub unknown: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DedupReport {
+    pub duplicates: Vec<Duplicate>,
+    pub conflicts: Vec<Conflict>,
+    pub discs: Vec<DiscSummary>,
+    pub files: usize,
+    pub reclaimable: u64,
+    pub unknown: usize,
+}
+
+/// Strips the project directory, so that the same file archived under
+/// different projects (e.g. `AnthonOS4` and `Repository`) has the same path.
+fn normalize_path(path: &str) -> &str {
+    path.split_once('/').map(|(_, p)| p).unwrap_or(path)
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Reads the entries of all the checksum lists in the directory, skipping
+/// the manifests at the root of each disc.
+fn read_disc_manifests(dir: &Path) -> Result<Vec<(DiscCopy, String)>> {
+    let mut entries = Vec::new();
+    for disc in list_discs(dir)? {
+        let md5_path = dir.join(md5_name(disc));
+        let checksums = parse_md5(&std::fs::read_to_string(&md5_path)?)
+            .with_context(|| format!("when parsing {}", md5_path.display()))?;
+        for (md5, path) in checksums {
+            if path.contains('/') {
+                let disc = disc as i64;
+                entries.push((DiscCopy { disc, path }, md5));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Groups the entries by checksum and by path, and plans which copies to
+/// remove. `size_of` returns the size of a copy, if known.
+pub fn plan_dedup<F>(entries: Vec<(DiscCopy, String)>, size_of: F) -> DedupReport
+where
+    F: Fn(&DiscCopy) -> Option<u64>,
+{
+    let mut by_md5: BTreeMap<String, BTreeSet<DiscCopy>> = BTreeMap::new();
+    let mut by_path: BTreeMap<String, BTreeSet<(DiscCopy, String)>> = BTreeMap::new();
+    for (copy, md5) in entries {
+        by_path
+            .entry(normalize_path(&copy.path).to_owned())
+            .or_default()
+            .insert((copy.clone(), md5.clone()));
+        by_md5.entry(md5).or_default().insert(copy);
+    }
+    let mut report = DedupReport::default();
+    let mut discs: BTreeMap<i64, DiscSummary> = BTreeMap::new();
+    for (md5, copies) in by_md5 {
+        if md5 == EMPTY_MD5 {
+            continue;
+        }
+        let mut copies = copies.into_iter();
+        let Some(keep) = copies.next() else {
+            continue;
+        };
+        // copies on the same disc are out of scope
+        let remove = copies.filter(|c| c.disc != keep.disc).collect::<Vec<_>>();
+        if remove.is_empty() {
+            continue;
+        }
+        let size = std::iter::once(&keep)
+            .chain(remove.iter())
+            .find_map(&size_of);
+        for copy in remove.iter() {
+            let summary = discs.entry(copy.disc).or_insert_with(|| DiscSummary {
+                disc: copy.disc,
+                ..Default::default()
+            });
+            summary.files += 1;
+            match size {
+                Some(size) => summary.reclaimable += size,
+                None => summary.unknown += 1,
+            }
+        }
+        report.duplicates.push(Duplicate {
+            md5,
+            size,
+            keep,
+            remove,
+        });
+    }
+    for (path, copies) in by_path {
+        let checksums = copies.iter().map(|(_, md5)| md5).collect::<BTreeSet<_>>();
+        let on_discs = copies.iter().map(|(c, _)| c.disc).collect::<BTreeSet<_>>();
+        if checksums.len() > 1 && on_discs.len() > 1 {
+            report.conflicts.push(Conflict {
+                path,
+                copies: copies.into_iter().collect(),
+            });
+        }
+    }
+    report.discs = discs.into_values().collect();
+    for summary in report.discs.iter() {
+        report.files += summary.files;
+        report.reclaimable += summary.reclaimable;
+        report.unknown += summary.unknown;
+    }
+
+    report
+}
+
+fn render_plan(report: &DedupReport) -> Result<String> {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# {} copies of {} files can be removed, {} reclaimable ({} files of unknown size)",
+        report.files,
+        report.duplicates.len(),
+        ByteSize::b(report.reclaimable).to_string_as(true),
+        report.unknown
+    )?;
+    // file to remove -> copy to keep
+    let mut by_disc: BTreeMap<i64, Vec<(&DiscCopy, &DiscCopy)>> = BTreeMap::new();
+    for dup in report.duplicates.iter() {
+        for copy in dup.remove.iter() {
+            by_disc
+                .entry(copy.disc)
+                .or_default()
+                .push((copy, &dup.keep));
+        }
+    }
+    for summary in report.discs.iter() {
+        writeln!(
+            out,
+            "\n{}: remove {} files, {} ({} files of unknown size)",
+            volume_name(summary.disc as usize),
+            summary.files,
+            ByteSize::b(summary.reclaimable).to_string_as(true),
+            summary.unknown
+        )?;
+        let mut files = by_disc.remove(&summary.disc).unwrap_or_default();
+        files.sort_unstable();
+        for (copy, keep) in files {
+            writeln!(
+                out,
+                "  ./{} -> {}:./{}",
+                copy.path,
+                volume_name(keep.disc as usize),
+                keep.path
+            )?;
+        }
+    }
+    if !report.conflicts.is_empty() {
+        writeln!(
+            out,
+            "\n# {} paths have different contents on different discs, keep all of them",
+            report.conflicts.len()
+        )?;
+        for conflict in report.conflicts.iter() {
+            let copies = conflict
+                .copies
+                .iter()
+                .map(|(c, md5)| format!("{} ({})", volume_name(c.disc as usize), md5))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(out, "  {}: {}", conflict.path, copies)?;
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn dedup_report_action(
+    input: Option<&str>,
+    catalog: Option<&str>,
+    labels: &[String],
+    volumes: Option<&str>,
+    output: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    if input.is_none() && catalog.is_none() {
+        bail!("Either a directory of disc manifests or a catalog database is required");
+    }
+    let mut entries = Vec::new();
+    if let Some(input) = input {
+        info!("Reading disc manifests from {} ...", input);
+        entries.extend(read_disc_manifests(Path::new(input))?);
+    }
+    if let Some(catalog) = catalog {
+        info!("Reading the catalog {} ...", catalog);
+        let catalog_entries =
+            load_disc_entries(catalog).with_context(|| format!("when reading {}", catalog))?;
+        entries.extend(
+            catalog_entries
+                .into_iter()
+                .map(|(disc, e)| (DiscCopy { disc, path: e.path }, e.md5)),
+        );
+    }
+    let mut sizes = HashMap::new();
+    for db_path in find_label_dbs(labels)? {
+        let labels = load_package_sizes(&db_path)
+            .with_context(|| format!("when reading {}", db_path.display()))?;
+        for (filename, size) in labels {
+            sizes.insert(file_name(&filename).to_owned(), size as u64);
+        }
+    }
+    info!("Grouping {} entries ...", entries.len());
+    let report = plan_dedup(entries, |copy| {
+        // the contents of the discs, e.g. the output of `binning` or the
+        // mount points of the discs
+        if let Some(volumes) = volumes {
+            let path = Path::new(volumes)
+                .join(volume_name(copy.disc as usize))
+                .join(&copy.path);
+            if let Ok(metadata) = path.metadata() {
+                return Some(metadata.len());
+            }
+        }
+        sizes.get(file_name(&copy.path)).copied()
+    });
+    info!(
+        "{} files are stored on more than one disc, {} reclaimable",
+        report.duplicates.len(),
+        ByteSize::b(report.reclaimable).to_string_as(true)
+    );
+    let content = if json {
+        serde_json::to_string_pretty(&report)?
+    } else {
+        render_plan(&report)?
+    };
+    match output {
+        Some(output) => {
+            std::fs::write(output, content).with_context(|| format!("when writing {}", output))?
+        }
+        None => print!("{}", content),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_plan_dedup() -> Result<()> {
+    let copy = |disc, path: &str, md5: &str| {
+        (
+            DiscCopy {
+                disc,
+                path: path.to_owned(),
+            },
+            md5.to_owned(),
+        )
+    };
+    let entries = vec![
+        copy(7, "Repository/os-armel/l/lxdm_0.5.0-0_armel.deb", "a"),
+        copy(20, "AnthonOS4/os-armel/l/lxdm_0.5.0-0_armel.deb", "a"),
+        copy(21, "Repository/os-armel/l/lxdm_0.5.0-0_armel.deb", "a"),
+        // imported twice, from the manifests and the catalog
+        copy(21, "Repository/os-armel/l/lxdm_0.5.0-0_armel.deb", "a"),
+        copy(7, "Repository/os-armel/x/xz_5.2-0_armel.deb", "b"),
+        copy(7, "Repository/os-armel/x/xz_5.2-1_armel.deb", "b"),
+        copy(20, "Repository/os-armel/x/xz_5.2-0_armel.deb", "c"),
+        copy(8, "Repository/os-armel/z/zsh_5.5-0_armel.deb", "d"),
+    ];
+    let report = plan_dedup(entries, |c| c.path.contains("lxdm").then_some(3000));
+    assert_eq!(report.duplicates.len(), 1);
+    assert_eq!(report.duplicates[0].keep.disc, 7);
+    assert_eq!(report.duplicates[0].remove.len(), 2);
+    assert_eq!(report.files, 2);
+    assert_eq!(report.reclaimable, 6000);
+    assert_eq!(report.unknown, 0);
+    assert_eq!(report.discs.len(), 2);
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].path, "os-armel/x/xz_5.2-0_armel.deb");
+    let plan = render_plan(&report)?;
+    assert!(plan.contains(
+        "  ./AnthonOS4/os-armel/l/lxdm_0.5.0-0_armel.deb -> disc-7:./Repository/os-armel/l/lxdm_0.5.0-0_armel.deb"
+    ));
+    // the manifests of the real discs
+    let report = plan_dedup(read_disc_manifests(Path::new("../Disc"))?, |_| None);
+    assert!(report.files > 0);
+    assert_eq!(report.unknown, report.files);
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Versions that the packages staying in the pool still depend on.
+//!
+//! A package with `Depends: foo (= 1.2-1)` or `foo (<< 1.3)` breaks when the
+//! older versions of foo are retired. A candidate is kept when it is the
+//! newest match of such a dependency, and no package staying in the pool
+//! satisfies it. Kept candidates stay in the pool along with their own
+//! dependencies, so this is repeated until nothing changes.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::db::{Dependency, PackageMeta, PoolVersion, Reason, RetiredPackages};
+use crate::version::compare_versions;
+
+/// A package in a dependency field, with its version constraint.
+#[derive(Debug, PartialEq, Eq)]
+struct Relation<'a> {
+    package: &'a str,
+    constraint: Option<(&'a str, &'a str)>,
+}
+
+impl Relation<'_> {
+    /// Whether only versions up to some version satisfy the relation.
+    fn is_bounded(&self) -> bool {
+        matches!(self.constraint, Some(("=" | "<<" | "<=" | "<", _)))
+    }
+
+    fn satisfied_by(&self, version: &str) -> bool {
+        let Some((op, wanted)) = self.constraint else {
+            return true;
+        };
+        let order = compare_versions(version, wanted);
+        match op {
+            "=" => order == Ordering::Equal,
+            "<<" => order == Ordering::Less,
+            // `<` and `>` are the obsolete forms of `<=` and `>=`
+            "<=" | "<" => order != Ordering::Greater,
+            ">>" => order == Ordering::Greater,
+            ">=" | ">" => order != Ordering::Less,
+            _ => false,
+        }
+    }
+}
+
+/// Parses a dependency field into its clauses, each of which is satisfied
+/// by any one of its alternatives.
+fn parse_relations(value: &str) -> Vec<Vec<Relation<'_>>> {
+    value
+        .split(',')
+        .map(|clause| {
+            clause
+                .split('|')
+                .filter_map(|alternative| {
+                    let alternative = alternative.trim();
+                    let (name, rest) = alternative.split_at(
+                        alternative
+                            .find(|c: char| c.is_whitespace() || c == '(')
+                            .unwrap_or(alternative.len()),
+                    );
+                    // leaves out the architecture qualifier, as in `foo:any`
+                    let package = name.split(':').next().unwrap_or(name);
+                    if package.is_empty() {
+                        return None;
+                    }
+                    let constraint = rest
+                        .split_once('(')
+                        .and_then(|(_, c)| c.split_once(')'))
+                        .map(|(c, _)| c.trim())
+                        .and_then(|c| {
+                            let op_end = c
+                                .find(|c: char| !matches!(c, '<' | '>' | '='))
+                                .unwrap_or(c.len());
+                            let (op, version) = c.split_at(op_end);
+                            (!op.is_empty()).then(|| (op, version.trim()))
+                        });
+
+                    Some(Relation {
+                        package,
+                        constraint,
+                    })
+                })
+                .collect()
+        })
+        .filter(|clause: &Vec<_>| !clause.is_empty())
+        .collect()
+}
+
+fn branch(repo: &str) -> &str {
+    repo.split_once('/').map_or(repo, |(_, branch)| branch)
+}
+
+fn is_noarch(arch: &str) -> bool {
+    matches!(arch, "all" | "noarch")
+}
+
+/// Whether the package can be installed along with the dependent, that is
+/// built for its architecture or for all, in its branch or in stable. A
+/// dependent built for all is installed with packages of any architecture.
+fn installable_with(p: &PoolVersion, dependent: &Dependency) -> bool {
+    (p.architecture == dependent.architecture
+        || is_noarch(&p.architecture)
+        || is_noarch(&dependent.architecture))
+        && (branch(&p.repo) == branch(&dependent.repo) || branch(&p.repo) == "stable")
+}
+
+/// Splits the candidates into those to retire, and those still depended on
+/// along with the packages needing them.
+pub fn keep_depended_on(
+    candidates: Vec<(PackageMeta, Reason)>,
+    versions: &[PoolVersion],
+    dependencies: &[Dependency],
+) -> RetiredPackages {
+    let retiring = candidates
+        .iter()
+        .map(|(p, _)| p.filename.as_str())
+        .collect::<HashSet<_>>();
+    let mut by_name: HashMap<&str, Vec<&PoolVersion>> = HashMap::new();
+    for p in versions {
+        by_name.entry(&p.package).or_default().push(p);
+    }
+    let parsed = dependencies
+        .iter()
+        .map(|d| (d, parse_relations(&d.value)))
+        .collect::<Vec<_>>();
+    let mut needed: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+    loop {
+        let kept = needed.len();
+        for (dependent, clauses) in parsed.iter() {
+            let filename = dependent.filename.as_str();
+            if retiring.contains(filename) && !needed.contains_key(filename) {
+                continue;
+            }
+            for clause in clauses {
+                let matches = |r: &Relation| {
+                    by_name
+                        .get(r.package)
+                        .into_iter()
+                        .flatten()
+                        .filter(|p| installable_with(p, dependent) && r.satisfied_by(&p.version))
+                        .copied()
+                        .collect::<Vec<_>>()
+                };
+                let satisfied = clause
+                    .iter()
+                    .flat_map(matches)
+                    .any(|p| !retiring.contains(p.filename.as_str()));
+                if satisfied {
+                    continue;
+                }
+                // prefers a version kept already, then the newest one
+                let chosen = clause
+                    .iter()
+                    .filter(|r| r.is_bounded())
+                    .flat_map(matches)
+                    .max_by(|a, b| {
+                        needed
+                            .contains_key(a.filename.as_str())
+                            .cmp(&needed.contains_key(b.filename.as_str()))
+                            .then_with(|| compare_versions(&a.version, &b.version))
+                    });
+                if let Some(p) = chosen {
+                    needed.entry(&p.filename).or_default().insert(format!(
+                        "{} {} ({})",
+                        dependent.package, dependent.version, dependent.repo
+                    ));
+                }
+            }
+        }
+        if needed.len() == kept {
+            break;
+        }
+    }
+
+    let mut kept = Vec::new();
+    let mut seen = HashSet::new();
+    let packages = candidates
+        .into_iter()
+        .filter_map(|(p, reason)| {
+            let Some(dependents) = needed.get(p.filename.as_str()) else {
+                return Some((p, reason));
+            };
+            if seen.insert(p.filename.clone()) {
+                let why = format!(
+                    "needed by {}",
+                    dependents.iter().cloned().collect::<Vec<_>>().join(", ")
+                );
+                kept.push((p, why));
+            }
+            None
+        })
+        .collect();
+
+    RetiredPackages { packages, kept }
+}
+
+#[test]
+fn test_keep_depended_on() {
+    let version = |name: &str, version: &str, arch: &str| {
+        let p = crate::testing::package(name, version, arch);
+        PoolVersion {
+            package: p.package,
+            version: p.version,
+            architecture: p.architecture,
+            repo: p.repo,
+            filename: p.filename,
+        }
+    };
+    let meta = |p: &PoolVersion| crate::testing::package(&p.package, &p.version, &p.architecture);
+    let depends = |p: &PoolVersion, value: &str| Dependency {
+        package: p.package.clone(),
+        version: p.version.clone(),
+        architecture: p.architecture.clone(),
+        repo: p.repo.clone(),
+        filename: p.filename.clone(),
+        value: value.to_owned(),
+    };
+    assert_eq!(
+        parse_relations("libfoo:any (= 1.2-1) | bar, baz (<<2.0)"),
+        vec![
+            vec![
+                Relation {
+                    package: "libfoo",
+                    constraint: Some(("=", "1.2-1"))
+                },
+                Relation {
+                    package: "bar",
+                    constraint: None
+                }
+            ],
+            vec![Relation {
+                package: "baz",
+                constraint: Some(("<<", "2.0"))
+            }]
+        ]
+    );
+    let versions = vec![
+        version("foo", "1.1-0", "amd64"),
+        version("foo", "1.2-1", "amd64"),
+        version("foo", "1.3-0", "amd64"),
+        version("bar", "1.0", "amd64"),
+        version("bar", "2.0", "amd64"),
+        version("baz", "0.9", "noarch"),
+        version("baz", "1.0", "noarch"),
+        version("qux", "1.0", "amd64"),
+        version("app", "1.0", "amd64"),
+        version("old", "1.0", "amd64"),
+        version("libfoo", "1.0", "arm64"),
+        version("libfoo", "1.1", "arm64"),
+        version("docs", "1.0", "noarch"),
+    ];
+    let candidates = [0, 1, 3, 5, 9, 10]
+        .iter()
+        .map(|&i| (meta(&versions[i]), Reason::Superseded))
+        .collect();
+    let dependencies = vec![
+        // only a version being retired satisfies these
+        depends(&versions[8], "foo (= 1.2-1), libc (>= 2.36)"),
+        depends(&versions[7], "foo (<< 1.3)"),
+        // the kept version of foo needs an older bar
+        depends(&versions[1], "bar (<= 1.0)"),
+        // satisfied by a version staying in the pool
+        depends(&versions[8], "baz (<= 1.0)"),
+        // the dependent is retired as well
+        depends(&versions[9], "baz (= 0.9)"),
+        // a package for all needs a package built for an architecture
+        depends(&versions[12], "libfoo (<< 1.1)"),
+    ];
+    let RetiredPackages { packages, kept } = keep_depended_on(candidates, &versions, &dependencies);
+    let retired = packages
+        .iter()
+        .map(|(p, _)| format!("{} {}", p.package, p.version))
+        .collect::<Vec<_>>();
+    let kept = kept
+        .iter()
+        .map(|(p, why)| format!("{} {}: {}", p.package, p.version, why))
+        .collect::<Vec<_>>();
+    assert_eq!(retired, vec!["foo 1.1-0", "baz 0.9", "old 1.0"]);
+    assert_eq!(
+        kept,
+        vec![
+            "foo 1.2-1: needed by app 1.0 (amd64/stable), qux 1.0 (amd64/stable)",
+            "bar 1.0: needed by foo 1.2-1 (amd64/stable)",
+            "libfoo 1.0: needed by docs 1.0 (noarch/stable)"
+        ]
+    );
+}
@@ -0,0 +1,1100 @@
+//! A minimal writer of ISO 9660 + UDF 1.02 bridge images.
+//!
+//! The layout of the image is as follows (in 2048-byte sectors):
+//!
+//! - 0-15: system area
+//! - 16-17: ISO 9660 primary volume descriptor and set terminator
+//! - 18-20: UDF volume recognition sequence
+//! - 32-37, 48-53: UDF main and reserve volume descriptor sequences
+//! - 64-65: UDF logical volume integrity sequence
+//! - 256: UDF anchor volume descriptor pointer
+//! - 257-: UDF partition, holding the file set descriptor, the file entries
+//!   and directories of both file systems, and the file data shared by both
+//! - last sector: UDF anchor volume descriptor pointer
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use log::info;
+
+use crate::binning::SECTOR_SIZE;
+use crate::manifest::{md5_name, tree_name};
+
+const BLOCK: usize = SECTOR_SIZE as usize;
+const ISO_PVD_LBA: u32 = 16;
+const VRS_LBA: u32 = 18;
+const MVDS_LBA: u32 = 32;
+const RVDS_LBA: u32 = 48;
+const VDS_BLOCKS: u32 = 6;
+const LVID_LBA: u32 = 64;
+const AVDP_LBA: u32 = 256;
+const PARTITION_LBA: u32 = 257;
+/// Largest extent a short allocation descriptor can describe
+const MAX_UDF_EXTENT: u64 = 0x3fff_f800;
+/// Largest extent an ISO 9660 directory record can describe
+const MAX_ISO_EXTENT: u64 = 0xffff_f800;
+/// Longest name that still fits into an ISO 9660 directory record
+const MAX_NAME_LEN: usize = 207;
+
+/// Space to set aside on every volume for the volume descriptors and the
+/// root directory of the image.
+pub const IMAGE_RESERVE: u64 = 272 * SECTOR_SIZE;
+
+pub fn volume_label(number: usize) -> String {
+    format!("AOSCDisc-{}", number)
+}
+
+#[inline]
+fn blocks(size: u64) -> u32 {
+    size.div_ceil(SECTOR_SIZE) as u32
+}
+
+#[inline]
+fn fid_len(name_len: usize) -> usize {
+    (38 + name_len).next_multiple_of(4)
+}
+
+#[inline]
+fn iso_record_len(name_len: usize) -> usize {
+    (33 + name_len).next_multiple_of(2)
+}
+
+/// Upper bound of the space a file named `name` takes in the file system
+/// structures of the image, besides its data.
+pub fn file_overhead(name: &Path, size: u64) -> u64 {
+    let len = name.file_name().map(|n| n.len()).unwrap_or_default();
+    let extents = size.div_ceil(MAX_ISO_EXTENT).max(1);
+    // the file entry, the FID (in UTF-16 at worst), and the ISO 9660
+    // records, which can not cross sectors
+    SECTOR_SIZE + fid_len(len * 2 + 1) as u64 + 2 * extents * iso_record_len(len + 2) as u64
+}
+
+/// Upper bound of the space a directory named `name` takes in the file
+/// system structures of the image.
+pub fn dir_overhead(name: &Path) -> u64 {
+    let len = name.file_name().map(|n| n.len()).unwrap_or_default();
+    // the file entry, the rounding of both directory extents, the parent
+    // FID, the `.` and `..` records, and both path table entries
+    3 * SECTOR_SIZE
+        + file_overhead(name, 0)
+        + 40
+        + 2 * iso_record_len(1) as u64
+        + 2 * (8 + len as u64 + 1)
+}
+
+struct Node {
+    name: String,
+    path: PathBuf,
+    mtime: DateTime<Utc>,
+    executable: bool,
+    is_dir: bool,
+    size: u64,
+    parent: usize,
+    children: Vec<usize>,
+    /// Sector of the UDF file entry
+    fe: u32,
+    /// Sector of the file data, or the UDF directory data
+    data: u32,
+    /// Size of the UDF directory data
+    udf_dir_len: u64,
+    /// Sector of the ISO 9660 directory extent
+    iso_dir: u32,
+    iso_dir_len: u64,
+}
+
+fn scan_volume(arena: &mut Vec<Node>, path: &Path, name: String, parent: usize) -> Result<usize> {
+    let metadata = std::fs::metadata(path)?;
+    let index = arena.len();
+    arena.push(Node {
+        name,
+        path: path.to_owned(),
+        mtime: metadata.modified()?.into(),
+        executable: std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o111 != 0,
+        is_dir: metadata.is_dir(),
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        parent,
+        children: Vec::new(),
+        fe: 0,
+        data: 0,
+        udf_dir_len: 0,
+        iso_dir: 0,
+        iso_dir_len: 0,
+    });
+    if !metadata.is_dir() {
+        return Ok(index);
+    }
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().map_err(|n| {
+            anyhow::anyhow!("File name is not valid UTF-8: {}", n.to_string_lossy())
+        })?;
+        if name.len() > MAX_NAME_LEN {
+            bail!("File name is too long: {}", name);
+        }
+        names.push(name);
+    }
+    names.sort_unstable();
+    for name in names {
+        let child = scan_volume(arena, &path.join(&name), name, index)?;
+        arena[index].children.push(child);
+    }
+
+    Ok(index)
+}
+
+/// CRC-ITU-T as used by ECMA-167 descriptor tags.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Fills in the descriptor tag at the start of `buf`.
+fn tag(buf: &mut [u8], id: u16, location: u32) {
+    buf[0..2].copy_from_slice(&id.to_le_bytes());
+    buf[2..4].copy_from_slice(&2u16.to_le_bytes());
+    let crc_len = buf.len() - 16;
+    let crc = crc16(&buf[16..]);
+    buf[8..10].copy_from_slice(&crc.to_le_bytes());
+    buf[10..12].copy_from_slice(&(crc_len as u16).to_le_bytes());
+    buf[12..16].copy_from_slice(&location.to_le_bytes());
+    buf[4] = buf[0..4]
+        .iter()
+        .chain(&buf[5..16])
+        .fold(0u8, |s, b| s.wrapping_add(*b));
+}
+
+/// Encodes the name as OSTA compressed unicode.
+fn dchars(s: &str) -> Vec<u8> {
+    if s.chars().all(|c| (c as u32) < 0x100) {
+        let mut out = vec![8];
+        out.extend(s.chars().map(|c| c as u8));
+        out
+    } else {
+        let mut out = vec![16];
+        out.extend(s.encode_utf16().flat_map(|c| c.to_be_bytes()));
+        out
+    }
+}
+
+fn dstring(s: &str, len: usize) -> Vec<u8> {
+    let mut out = vec![0; len];
+    if !s.is_empty() {
+        let chars = dchars(s);
+        let used = chars.len().min(len - 1);
+        out[..used].copy_from_slice(&chars[..used]);
+        out[len - 1] = used as u8;
+    }
+
+    out
+}
+
+fn parse_dstring(buf: &[u8]) -> String {
+    let used = (buf[buf.len() - 1] as usize).min(buf.len() - 1);
+    match buf.first() {
+        Some(8) => buf[1..used].iter().map(|b| *b as char).collect(),
+        Some(16) => String::from_utf16_lossy(
+            &buf[1..used]
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        _ => String::new(),
+    }
+}
+
+fn charspec() -> [u8; 64] {
+    let mut out = [0; 64];
+    out[1..24].copy_from_slice(b"OSTA Compressed Unicode");
+    out
+}
+
+fn regid(id: &str, suffix: &[u8]) -> [u8; 32] {
+    let mut out = [0; 32];
+    out[1..1 + id.len()].copy_from_slice(id.as_bytes());
+    out[24..24 + suffix.len()].copy_from_slice(suffix);
+    out
+}
+
+fn domain_regid() -> [u8; 32] {
+    // UDF revision 1.02
+    regid("*OSTA UDF Compliant", &[0x02, 0x01])
+}
+
+fn impl_regid() -> [u8; 32] {
+    regid("*AOSC Archive", &[])
+}
+
+fn udf_timestamp(t: &DateTime<Utc>) -> [u8; 12] {
+    let mut out = [0; 12];
+    // type 1 (local time), at UTC
+    out[0..2].copy_from_slice(&(1u16 << 12).to_le_bytes());
+    out[2..4].copy_from_slice(&(t.year() as u16).to_le_bytes());
+    out[4] = t.month() as u8;
+    out[5] = t.day() as u8;
+    out[6] = t.hour() as u8;
+    out[7] = t.minute() as u8;
+    out[8] = t.second() as u8;
+    out
+}
+
+fn short_ads(len: u64, lba: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let extent = (len - offset).min(MAX_UDF_EXTENT);
+        let lbn = lba - PARTITION_LBA + blocks(offset);
+        out.extend((extent as u32).to_le_bytes());
+        out.extend(lbn.to_le_bytes());
+        offset += extent;
+    }
+
+    out
+}
+
+fn long_ad(lba: u32) -> [u8; 16] {
+    let mut out = [0; 16];
+    out[0..4].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+    out[4..8].copy_from_slice(&(lba - PARTITION_LBA).to_le_bytes());
+    out
+}
+
+fn both16(v: u16) -> [u8; 4] {
+    let mut out = [0; 4];
+    out[0..2].copy_from_slice(&v.to_le_bytes());
+    out[2..4].copy_from_slice(&v.to_be_bytes());
+    out
+}
+
+fn both32(v: u32) -> [u8; 8] {
+    let mut out = [0; 8];
+    out[0..4].copy_from_slice(&v.to_le_bytes());
+    out[4..8].copy_from_slice(&v.to_be_bytes());
+    out
+}
+
+fn iso_date(t: &DateTime<Utc>) -> [u8; 7] {
+    [
+        (t.year() - 1900) as u8,
+        t.month() as u8,
+        t.day() as u8,
+        t.hour() as u8,
+        t.minute() as u8,
+        t.second() as u8,
+        0,
+    ]
+}
+
+fn iso_datetime(t: &DateTime<Utc>) -> [u8; 17] {
+    let mut out = [0; 17];
+    out[..16].copy_from_slice(t.format("%Y%m%d%H%M%S00").to_string().as_bytes());
+    out
+}
+
+fn iso_record(id: &[u8], location: u32, length: u64, t: &DateTime<Utc>, flags: u8) -> Vec<u8> {
+    let mut out = vec![0; iso_record_len(id.len())];
+    out[0] = out.len() as u8;
+    out[2..10].copy_from_slice(&both32(location));
+    out[10..18].copy_from_slice(&both32(length as u32));
+    out[18..25].copy_from_slice(&iso_date(t));
+    out[25] = flags;
+    out[28..32].copy_from_slice(&both16(1));
+    out[32] = id.len() as u8;
+    out[33..33 + id.len()].copy_from_slice(id);
+    out
+}
+
+struct Image {
+    arena: Vec<Node>,
+    label: String,
+    now: DateTime<Utc>,
+    /// Directories in path table order
+    path_table: Vec<usize>,
+    path_table_len: u64,
+    l_path_table: u32,
+    m_path_table: u32,
+    total: u32,
+}
+
+impl Image {
+    fn new(volume_dir: &Path, label: String) -> Result<Self> {
+        let mut arena = Vec::new();
+        scan_volume(&mut arena, volume_dir, String::new(), 0)?;
+        let mut image = Image {
+            arena,
+            label,
+            now: Utc::now(),
+            path_table: Vec::new(),
+            path_table_len: 0,
+            l_path_table: 0,
+            m_path_table: 0,
+            total: 0,
+        };
+        image.allocate();
+
+        Ok(image)
+    }
+
+    fn dirs(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.arena.len()).filter(|i| self.arena[*i].is_dir)
+    }
+
+    /// Assigns sectors to every structure of the image.
+    fn allocate(&mut self) {
+        // the file set descriptor and its terminator come first
+        let mut cursor = PARTITION_LBA + 2;
+        for i in 0..self.arena.len() {
+            self.arena[i].fe = cursor;
+            cursor += 1;
+            if self.arena[i].is_dir {
+                let len = self.arena[i]
+                    .children
+                    .iter()
+                    .map(|c| fid_len(dchars(&self.arena[*c].name).len()))
+                    .sum::<usize>()
+                    + fid_len(0);
+                self.arena[i].udf_dir_len = len as u64;
+                self.arena[i].data = cursor;
+                cursor += blocks(len as u64);
+            }
+        }
+        // directories are numbered level by level
+        let mut queue = std::collections::VecDeque::from([0]);
+        while let Some(i) = queue.pop_front() {
+            self.path_table.push(i);
+            queue.extend(
+                self.arena[i]
+                    .children
+                    .iter()
+                    .filter(|c| self.arena[**c].is_dir),
+            );
+        }
+        self.path_table_len = self.path_table(true).len() as u64;
+        self.l_path_table = cursor;
+        cursor += blocks(self.path_table_len);
+        self.m_path_table = cursor;
+        cursor += blocks(self.path_table_len);
+        for i in self.dirs().collect::<Vec<_>>() {
+            let len = self.iso_dir(i).len() as u64;
+            self.arena[i].iso_dir = cursor;
+            self.arena[i].iso_dir_len = len;
+            cursor += blocks(len);
+        }
+        for node in self.arena.iter_mut().filter(|n| !n.is_dir && n.size > 0) {
+            node.data = cursor;
+            cursor += blocks(node.size);
+        }
+        // the last sector holds the second anchor
+        self.total = cursor + 1;
+    }
+
+    fn fid(&self, characteristics: u8, name: &str, icb: u32, offset: usize, dir: u32) -> Vec<u8> {
+        let name = if name.is_empty() {
+            Vec::new()
+        } else {
+            dchars(name)
+        };
+        let mut out = vec![0; fid_len(name.len())];
+        out[16..18].copy_from_slice(&1u16.to_le_bytes());
+        out[18] = characteristics;
+        out[19] = name.len() as u8;
+        out[20..36].copy_from_slice(&long_ad(icb));
+        out[38..38 + name.len()].copy_from_slice(&name);
+        let location = dir - PARTITION_LBA + (offset / BLOCK) as u32;
+        tag(&mut out, 257, location);
+        out
+    }
+
+    /// The file identifier descriptors of a directory.
+    fn udf_dir(&self, index: usize) -> Vec<u8> {
+        let node = &self.arena[index];
+        let mut out = self.fid(0x0a, "", self.arena[node.parent].fe, 0, node.data);
+        for child in node.children.iter() {
+            let child = &self.arena[*child];
+            let characteristics = if child.is_dir { 0x02 } else { 0 };
+            let fid = self.fid(characteristics, &child.name, child.fe, out.len(), node.data);
+            out.extend(fid);
+        }
+
+        out
+    }
+
+    fn file_entry(&self, index: usize) -> Vec<u8> {
+        let node = &self.arena[index];
+        let (len, ads) = if node.is_dir {
+            (node.udf_dir_len, short_ads(node.udf_dir_len, node.data))
+        } else {
+            (node.size, short_ads(node.size, node.data))
+        };
+        let mut out = vec![0; 176 + ads.len()];
+        // ICB tag: strategy 4, one entry
+        out[20..22].copy_from_slice(&4u16.to_le_bytes());
+        out[24..26].copy_from_slice(&1u16.to_le_bytes());
+        out[27] = if node.is_dir { 4 } else { 5 };
+        // owner, group and others may read (and search)
+        let mut permissions: u32 = (1 << 2) | (1 << 7) | (1 << 12);
+        if node.is_dir || node.executable {
+            permissions |= 1 | (1 << 5) | (1 << 10);
+        }
+        out[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        out[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        out[44..48].copy_from_slice(&permissions.to_le_bytes());
+        let links = if node.is_dir {
+            1 + node
+                .children
+                .iter()
+                .filter(|c| self.arena[**c].is_dir)
+                .count()
+        } else {
+            1
+        };
+        out[48..50].copy_from_slice(&(links as u16).to_le_bytes());
+        out[56..64].copy_from_slice(&len.to_le_bytes());
+        out[64..72].copy_from_slice(&(blocks(len) as u64).to_le_bytes());
+        let mtime = udf_timestamp(&node.mtime);
+        out[72..84].copy_from_slice(&mtime);
+        out[84..96].copy_from_slice(&mtime);
+        out[96..108].copy_from_slice(&mtime);
+        out[108..112].copy_from_slice(&1u32.to_le_bytes());
+        out[128..160].copy_from_slice(&impl_regid());
+        let unique_id = if index == 0 { 0 } else { 16 + index as u64 };
+        out[160..168].copy_from_slice(&unique_id.to_le_bytes());
+        out[172..176].copy_from_slice(&(ads.len() as u32).to_le_bytes());
+        out[176..].copy_from_slice(&ads);
+        tag(&mut out, 261, node.fe - PARTITION_LBA);
+        out
+    }
+
+    fn iso_records(&self, index: usize) -> Vec<Vec<u8>> {
+        let node = &self.arena[index];
+        if node.is_dir {
+            return vec![iso_record(
+                node.name.as_bytes(),
+                node.iso_dir,
+                node.iso_dir_len,
+                &node.mtime,
+                0x02,
+            )];
+        }
+        let id = format!("{};1", node.name);
+        let mut records = Vec::new();
+        let mut offset = 0;
+        loop {
+            let extent = (node.size - offset).min(MAX_ISO_EXTENT);
+            let last = offset + extent >= node.size;
+            let location = if node.size > 0 {
+                node.data + blocks(offset)
+            } else {
+                0
+            };
+            let flags = if last { 0 } else { 0x80 };
+            records.push(iso_record(
+                id.as_bytes(),
+                location,
+                extent,
+                &node.mtime,
+                flags,
+            ));
+            offset += extent;
+            if last {
+                return records;
+            }
+        }
+    }
+
+    /// The ISO 9660 directory extent of a directory.
+    fn iso_dir(&self, index: usize) -> Vec<u8> {
+        let node = &self.arena[index];
+        let parent = &self.arena[node.parent];
+        let mut records = vec![
+            iso_record(&[0], node.iso_dir, node.iso_dir_len, &node.mtime, 0x02),
+            iso_record(
+                &[1],
+                parent.iso_dir,
+                parent.iso_dir_len,
+                &parent.mtime,
+                0x02,
+            ),
+        ];
+        for child in node.children.iter() {
+            records.extend(self.iso_records(*child));
+        }
+        let mut out = Vec::new();
+        for record in records {
+            // records may not cross sector boundaries
+            if out.len() % BLOCK + record.len() > BLOCK {
+                out.resize(out.len().next_multiple_of(BLOCK), 0);
+            }
+            out.extend(record);
+        }
+        out.resize(out.len().next_multiple_of(BLOCK), 0);
+        out
+    }
+
+    fn path_table(&self, little_endian: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for i in self.path_table.iter() {
+            let node = &self.arena[*i];
+            let id = if *i == 0 {
+                &[0u8][..]
+            } else {
+                node.name.as_bytes()
+            };
+            let parent = self
+                .path_table
+                .iter()
+                .position(|p| *p == node.parent)
+                .unwrap_or_default()
+                + 1;
+            out.push(id.len() as u8);
+            out.push(0);
+            if little_endian {
+                out.extend(node.iso_dir.to_le_bytes());
+                out.extend((parent as u16).to_le_bytes());
+            } else {
+                out.extend(node.iso_dir.to_be_bytes());
+                out.extend((parent as u16).to_be_bytes());
+            }
+            out.extend(id);
+            if id.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+
+        out
+    }
+
+    fn iso_pvd(&self) -> Vec<u8> {
+        let mut out = vec![0; BLOCK];
+        out[0] = 1;
+        out[1..6].copy_from_slice(b"CD001");
+        out[6] = 1;
+        out[8..40].fill(b' ');
+        out[40..72].fill(b' ');
+        out[40..40 + self.label.len()].copy_from_slice(self.label.as_bytes());
+        out[80..88].copy_from_slice(&both32(self.total));
+        out[120..124].copy_from_slice(&both16(1));
+        out[124..128].copy_from_slice(&both16(1));
+        out[128..132].copy_from_slice(&both16(BLOCK as u16));
+        out[132..140].copy_from_slice(&both32(self.path_table_len as u32));
+        out[140..144].copy_from_slice(&self.l_path_table.to_le_bytes());
+        out[148..152].copy_from_slice(&self.m_path_table.to_be_bytes());
+        let root = &self.arena[0];
+        let record = iso_record(&[0], root.iso_dir, root.iso_dir_len, &root.mtime, 0x02);
+        out[156..190].copy_from_slice(&record);
+        out[190..813].fill(b' ');
+        let now = iso_datetime(&self.now);
+        out[813..830].copy_from_slice(&now);
+        out[830..847].copy_from_slice(&now);
+        out[847..863].fill(b'0');
+        out[864..881].copy_from_slice(&now);
+        out[881] = 1;
+        out
+    }
+
+    fn vrs(id: &[u8; 5]) -> Vec<u8> {
+        let mut out = vec![0; BLOCK];
+        out[1..6].copy_from_slice(id);
+        out[6] = 1;
+        out
+    }
+
+    fn udf_pvd(&self, location: u32) -> Vec<u8> {
+        let mut out = vec![0; 512];
+        out[16..20].copy_from_slice(&1u32.to_le_bytes());
+        out[24..56].copy_from_slice(&dstring(&self.label, 32));
+        out[56..58].copy_from_slice(&1u16.to_le_bytes());
+        out[58..60].copy_from_slice(&1u16.to_le_bytes());
+        out[60..62].copy_from_slice(&2u16.to_le_bytes());
+        out[62..64].copy_from_slice(&2u16.to_le_bytes());
+        out[64..68].copy_from_slice(&1u32.to_le_bytes());
+        out[68..72].copy_from_slice(&1u32.to_le_bytes());
+        let volume_set = format!("{:016X}{}", self.now.timestamp_micros(), self.label);
+        out[72..200].copy_from_slice(&dstring(&volume_set, 128));
+        out[200..264].copy_from_slice(&charspec());
+        out[264..328].copy_from_slice(&charspec());
+        out[344..376].copy_from_slice(&impl_regid());
+        out[376..388].copy_from_slice(&udf_timestamp(&self.now));
+        out[388..420].copy_from_slice(&impl_regid());
+        tag(&mut out, 1, location);
+        out
+    }
+
+    fn udf_iuvd(&self, location: u32) -> Vec<u8> {
+        let mut out = vec![0; 512];
+        out[16..20].copy_from_slice(&2u32.to_le_bytes());
+        out[20..52].copy_from_slice(&regid("*UDF LV Info", &[0x02, 0x01]));
+        out[52..116].copy_from_slice(&charspec());
+        out[116..244].copy_from_slice(&dstring(&self.label, 128));
+        out[352..384].copy_from_slice(&impl_regid());
+        tag(&mut out, 4, location);
+        out
+    }
+
+    fn udf_pd(&self, location: u32) -> Vec<u8> {
+        let mut out = vec![0; 512];
+        out[16..20].copy_from_slice(&3u32.to_le_bytes());
+        // allocated, partition number 0
+        out[20..22].copy_from_slice(&1u16.to_le_bytes());
+        out[24..56].copy_from_slice(&regid("+NSR02", &[]));
+        // read only
+        out[184..188].copy_from_slice(&1u32.to_le_bytes());
+        out[188..192].copy_from_slice(&PARTITION_LBA.to_le_bytes());
+        out[192..196].copy_from_slice(&(self.total - 1 - PARTITION_LBA).to_le_bytes());
+        out[196..228].copy_from_slice(&impl_regid());
+        tag(&mut out, 5, location);
+        out
+    }
+
+    fn udf_lvd(&self, location: u32) -> Vec<u8> {
+        let mut out = vec![0; 446];
+        out[16..20].copy_from_slice(&4u32.to_le_bytes());
+        out[20..84].copy_from_slice(&charspec());
+        out[84..212].copy_from_slice(&dstring(&self.label, 128));
+        out[212..216].copy_from_slice(&(BLOCK as u32).to_le_bytes());
+        out[216..248].copy_from_slice(&domain_regid());
+        out[248..264].copy_from_slice(&long_ad(PARTITION_LBA));
+        out[264..268].copy_from_slice(&6u32.to_le_bytes());
+        out[268..272].copy_from_slice(&1u32.to_le_bytes());
+        out[272..304].copy_from_slice(&impl_regid());
+        out[432..436].copy_from_slice(&(2 * BLOCK as u32).to_le_bytes());
+        out[436..440].copy_from_slice(&LVID_LBA.to_le_bytes());
+        // type 1 partition map for partition 0 on volume 1
+        out[440..446].copy_from_slice(&[1, 6, 1, 0, 0, 0]);
+        tag(&mut out, 6, location);
+        out
+    }
+
+    fn udf_usd(&self, location: u32) -> Vec<u8> {
+        let mut out = vec![0; 24];
+        out[16..20].copy_from_slice(&5u32.to_le_bytes());
+        tag(&mut out, 7, location);
+        out
+    }
+
+    fn terminator(location: u32) -> Vec<u8> {
+        let mut out = vec![0; 512];
+        tag(&mut out, 8, location);
+        out
+    }
+
+    fn udf_lvid(&self) -> Vec<u8> {
+        let mut out = vec![0; 134];
+        out[16..28].copy_from_slice(&udf_timestamp(&self.now));
+        // closed
+        out[28..32].copy_from_slice(&1u32.to_le_bytes());
+        out[40..48].copy_from_slice(&(16 + self.arena.len() as u64).to_le_bytes());
+        out[72..76].copy_from_slice(&1u32.to_le_bytes());
+        out[76..80].copy_from_slice(&46u32.to_le_bytes());
+        out[84..88].copy_from_slice(&(self.total - 1 - PARTITION_LBA).to_le_bytes());
+        out[88..120].copy_from_slice(&impl_regid());
+        let dirs = self.dirs().count() as u32;
+        out[120..124].copy_from_slice(&(self.arena.len() as u32 - dirs).to_le_bytes());
+        out[124..128].copy_from_slice(&dirs.to_le_bytes());
+        for offset in [128, 130, 132] {
+            out[offset..offset + 2].copy_from_slice(&0x0102u16.to_le_bytes());
+        }
+        tag(&mut out, 9, LVID_LBA);
+        out
+    }
+
+    fn udf_avdp(location: u32) -> Vec<u8> {
+        let mut out = vec![0; 512];
+        out[16..20].copy_from_slice(&(VDS_BLOCKS * BLOCK as u32).to_le_bytes());
+        out[20..24].copy_from_slice(&MVDS_LBA.to_le_bytes());
+        out[24..28].copy_from_slice(&(VDS_BLOCKS * BLOCK as u32).to_le_bytes());
+        out[28..32].copy_from_slice(&RVDS_LBA.to_le_bytes());
+        tag(&mut out, 2, location);
+        out
+    }
+
+    fn udf_fsd(&self) -> Vec<u8> {
+        let mut out = vec![0; 512];
+        out[16..28].copy_from_slice(&udf_timestamp(&self.now));
+        out[28..30].copy_from_slice(&3u16.to_le_bytes());
+        out[30..32].copy_from_slice(&3u16.to_le_bytes());
+        out[32..36].copy_from_slice(&1u32.to_le_bytes());
+        out[36..40].copy_from_slice(&1u32.to_le_bytes());
+        out[48..112].copy_from_slice(&charspec());
+        out[112..240].copy_from_slice(&dstring(&self.label, 128));
+        out[240..304].copy_from_slice(&charspec());
+        out[304..336].copy_from_slice(&dstring(&self.label, 32));
+        out[400..416].copy_from_slice(&long_ad(self.arena[0].fe));
+        out[416..448].copy_from_slice(&domain_regid());
+        tag(&mut out, 256, 0);
+        out
+    }
+
+    fn write<W: Write>(&self, out: &mut SectorWriter<W>) -> Result<()> {
+        out.write_at(ISO_PVD_LBA, &self.iso_pvd())?;
+        let mut terminator = vec![0; BLOCK];
+        terminator[0] = 255;
+        terminator[1..6].copy_from_slice(b"CD001");
+        terminator[6] = 1;
+        out.write_at(ISO_PVD_LBA + 1, &terminator)?;
+        for (i, id) in [b"BEA01", b"NSR02", b"TEA01"].iter().enumerate() {
+            out.write_at(VRS_LBA + i as u32, &Self::vrs(id))?;
+        }
+        for start in [MVDS_LBA, RVDS_LBA] {
+            out.write_at(start, &self.udf_pvd(start))?;
+            out.write_at(start + 1, &self.udf_iuvd(start + 1))?;
+            out.write_at(start + 2, &self.udf_pd(start + 2))?;
+            out.write_at(start + 3, &self.udf_lvd(start + 3))?;
+            out.write_at(start + 4, &self.udf_usd(start + 4))?;
+            out.write_at(start + 5, &Self::terminator(start + 5))?;
+        }
+        out.write_at(LVID_LBA, &self.udf_lvid())?;
+        out.write_at(LVID_LBA + 1, &Self::terminator(LVID_LBA + 1))?;
+        out.write_at(AVDP_LBA, &Self::udf_avdp(AVDP_LBA))?;
+        out.write_at(PARTITION_LBA, &self.udf_fsd())?;
+        out.write_at(PARTITION_LBA + 1, &Self::terminator(1))?;
+        for i in 0..self.arena.len() {
+            let node = &self.arena[i];
+            out.write_at(node.fe, &self.file_entry(i))?;
+            if node.is_dir {
+                out.write_at(node.data, &self.udf_dir(i))?;
+            }
+        }
+        out.write_at(self.l_path_table, &self.path_table(true))?;
+        out.write_at(self.m_path_table, &self.path_table(false))?;
+        for i in self.dirs() {
+            out.write_at(self.arena[i].iso_dir, &self.iso_dir(i))?;
+        }
+        for node in self.arena.iter().filter(|n| !n.is_dir && n.size > 0) {
+            let mut f = File::open(&node.path)
+                .with_context(|| format!("when reading {}", node.path.display()))?;
+            out.copy_at(node.data, &mut f, node.size)
+                .with_context(|| format!("when copying {}", node.path.display()))?;
+        }
+        out.write_at(self.total - 1, &Self::udf_avdp(self.total - 1))?;
+
+        Ok(())
+    }
+}
+
+/// Writes whole sectors in ascending order, filling the gaps with zeros.
+struct SectorWriter<W: Write> {
+    inner: W,
+    lba: u32,
+}
+
+impl<W: Write> SectorWriter<W> {
+    fn seek_to(&mut self, lba: u32) -> Result<()> {
+        if lba < self.lba {
+            bail!("Sector {} has already been written", lba);
+        }
+        let zeros = [0; BLOCK];
+        while self.lba < lba {
+            self.inner.write_all(&zeros)?;
+            self.lba += 1;
+        }
+
+        Ok(())
+    }
+
+    fn pad(&mut self, written: u64) -> Result<()> {
+        let padding = (written.next_multiple_of(SECTOR_SIZE) - written) as usize;
+        self.inner.write_all(&vec![0; padding])?;
+        self.lba += blocks(written);
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, lba: u32, data: &[u8]) -> Result<()> {
+        self.seek_to(lba)?;
+        self.inner.write_all(data)?;
+        self.pad(data.len() as u64)
+    }
+
+    fn copy_at<R: Read>(&mut self, lba: u32, data: &mut R, len: u64) -> Result<()> {
+        self.seek_to(lba)?;
+        let copied = std::io::copy(&mut data.take(len), &mut self.inner)?;
+        if copied != len {
+            bail!(
+                "File changed while being written: expected {} bytes, got {}",
+                len,
+                copied
+            );
+        }
+        self.pad(len)
+    }
+}
+
+/// Reads the volume labels back from the image and checks them.
+pub fn check_label(image: &Path, label: &str) -> Result<()> {
+    let mut f = File::open(image)?;
+    let mut read_sector = |lba: u32| -> Result<Vec<u8>> {
+        let mut buf = vec![0; BLOCK];
+        f.seek(SeekFrom::Start(lba as u64 * SECTOR_SIZE))?;
+        f.read_exact(&mut buf)?;
+        Ok(buf)
+    };
+    let pvd = read_sector(ISO_PVD_LBA)?;
+    if &pvd[1..6] != b"CD001" {
+        bail!("{} is not an ISO 9660 image", image.display());
+    }
+    let mut labels = vec![(
+        "ISO 9660",
+        String::from_utf8_lossy(&pvd[40..72]).trim_end().to_owned(),
+    )];
+    labels.push(("UDF volume", parse_dstring(&read_sector(MVDS_LBA)?[24..56])));
+    labels.push((
+        "UDF logical volume",
+        parse_dstring(&read_sector(MVDS_LBA + 3)?[84..212]),
+    ));
+    for (kind, found) in labels {
+        if found != label {
+            bail!(
+                "Wrong {} label in {}: expected {}, found {}",
+                kind,
+                image.display(),
+                label,
+                found
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the disc number of the volume from its manifests.
+fn volume_number(volume_dir: &Path) -> Result<usize> {
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(volume_dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(number) = name
+            .strip_prefix("disc-")
+            .and_then(|n| n.strip_suffix(".tree").or_else(|| n.strip_suffix(".md5")))
+        {
+            numbers.push(
+                number
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid manifest name: {}", name))?,
+            );
+        }
+    }
+    numbers.sort_unstable();
+    numbers.dedup();
+    let number = match numbers[..] {
+        [number] => number,
+        [] => bail!("No manifests found in {}", volume_dir.display()),
+        _ => bail!(
+            "Manifests of more than one disc found in {}: {:?}",
+            volume_dir.display(),
+            numbers
+        ),
+    };
+    for name in [tree_name(number), md5_name(number)] {
+        if !volume_dir.join(&name).is_file() {
+            bail!("Missing manifest {} in {}", name, volume_dir.display());
+        }
+    }
+    let md5 = std::fs::read_to_string(volume_dir.join(md5_name(number)))?;
+    if md5
+        .lines()
+        .any(|l| l.ends_with(&format!("./{}", md5_name(number))))
+    {
+        bail!("{} contains its own checksum", md5_name(number));
+    }
+
+    Ok(number)
+}
+
+pub fn mkimage_action<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
+    let input = input.as_ref();
+    let output = output.as_ref();
+    let number = volume_number(input)?;
+    let label = volume_label(number);
+    if let Some(name) = input.file_name() {
+        let name = name.to_string_lossy();
+        if name.starts_with("disc-") && name != crate::binning::volume_name(number) {
+            bail!(
+                "Volume directory {} does not match its manifests ({})",
+                input.display(),
+                tree_name(number)
+            );
+        }
+    }
+    info!("Scanning {} ...", input.display());
+    let image = Image::new(input, label.clone())?;
+    info!(
+        "Writing {} ({} files, {} sectors) to {} ...",
+        label,
+        image.arena.len(),
+        image.total,
+        output.display()
+    );
+    let f = File::create(output)?;
+    let mut writer = SectorWriter {
+        inner: BufWriter::new(f),
+        lba: 0,
+    };
+    image.write(&mut writer)?;
+    writer.inner.into_inner()?.sync_all()?;
+    info!("Checking volume labels ...");
+    check_label(output, &label)?;
+    info!("Successfully created {}", output.display());
+
+    Ok(())
+}
+
+#[test]
+fn test_mkimage() -> Result<()> {
+    use crate::manifest::write_manifests;
+    let root = crate::testing::TempDir::new("image")?;
+    let volume_dir = root.join("disc-49");
+    std::fs::create_dir_all(volume_dir.join("Repository/stable/main/a"))?;
+    std::fs::write(
+        volume_dir.join("Repository/stable/main/a/a_1_amd64.deb"),
+        vec![0x55; 5000],
+    )?;
+    write_manifests(&volume_dir, 49)?;
+    let output = root.join("disc-49.iso");
+    mkimage_action(&volume_dir, &output)?;
+    let size = std::fs::metadata(&output)?.len();
+    let label = check_label(&output, "AOSCDisc-49");
+    let wrong_label = check_label(&output, "AOSCDisc-4");
+    // renamed volume directories are refused
+    std::fs::rename(&volume_dir, root.join("disc-2"))?;
+    let renamed = mkimage_action(&root.join("disc-2"), &output);
+    drop(root);
+    assert_eq!(size % SECTOR_SIZE, 0);
+    label?;
+    assert!(wrong_label.is_err());
+    assert!(renamed.is_err());
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+    Ok(())
+}
+
+#[test]
+fn test_image_layout() -> Result<()> {
+    use crate::manifest::write_manifests;
+    let root = crate::testing::TempDir::new("image-layout")?;
+    let volume_dir = root.join("disc-50");
+    let content = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+    std::fs::create_dir_all(volume_dir.join("Repository/stable/main/w"))?;
+    std::fs::write(
+        volume_dir.join("Repository/stable/main/w/wget_1.21_amd64.deb"),
+        &content,
+    )?;
+    write_manifests(&volume_dir, 50)?;
+    let output = root.join("disc-50.iso");
+    mkimage_action(&volume_dir, &output)?;
+    let image = std::fs::read(&output)?;
+    drop(root);
+    let sector = |lba: u32| &image[lba as usize * BLOCK..(lba as usize + 1) * BLOCK];
+    let u32_at =
+        |buf: &[u8], offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+    // checks the tag of the descriptor and returns it
+    let descriptor = |lba: u32, id: u16, location: u32| {
+        let buf = sector(lba);
+        assert_eq!(u16::from_le_bytes([buf[0], buf[1]]), id, "tag at {}", lba);
+        let checksum = buf[0..4]
+            .iter()
+            .chain(&buf[5..16])
+            .fold(0u8, |s, b| s.wrapping_add(*b));
+        assert_eq!(buf[4], checksum, "tag checksum at {}", lba);
+        let crc_len = u16::from_le_bytes([buf[10], buf[11]]) as usize;
+        assert_eq!(
+            crc16(&buf[16..16 + crc_len]),
+            u16::from_le_bytes([buf[8], buf[9]]),
+            "descriptor CRC at {}",
+            lba
+        );
+        assert_eq!(u32_at(buf, 12), location, "tag location at {}", lba);
+        buf
+    };
+
+    // volume descriptors
+    let pvd = sector(ISO_PVD_LBA);
+    assert_eq!((pvd[0], &pvd[1..6]), (1, &b"CD001"[..]));
+    assert_eq!(&pvd[40..51], b"AOSCDisc-50");
+    let total = u32_at(pvd, 80);
+    assert_eq!(image.len(), total as usize * BLOCK);
+    assert_eq!(sector(ISO_PVD_LBA + 1)[0], 255);
+    for (i, id) in [b"BEA01", b"NSR02", b"TEA01"].iter().enumerate() {
+        assert_eq!(&sector(VRS_LBA + i as u32)[1..6], *id);
+    }
+    for lba in [AVDP_LBA, total - 1] {
+        let avdp = descriptor(lba, 2, lba);
+        assert_eq!(u32_at(avdp, 20), MVDS_LBA);
+        assert_eq!(u32_at(avdp, 28), RVDS_LBA);
+    }
+    for start in [MVDS_LBA, RVDS_LBA] {
+        for (i, id) in [1, 4, 5, 6, 7, 8].into_iter().enumerate() {
+            descriptor(start + i as u32, id, start + i as u32);
+        }
+    }
+    let pd = sector(MVDS_LBA + 2);
+    assert_eq!(u32_at(pd, 188), PARTITION_LBA);
+    descriptor(LVID_LBA, 9, LVID_LBA);
+
+    // the file through the ISO 9660 directory records
+    let mut record = &pvd[156..190];
+    for name in ["Repository", "stable", "main", "w", "wget_1.21_amd64.deb;1"] {
+        let extent = u32_at(record, 2) as usize * BLOCK;
+        let dir = &image[extent..extent + u32_at(record, 10) as usize];
+        let mut offset = 0;
+        record = loop {
+            assert!(offset < dir.len(), "{} not found", name);
+            let len = dir[offset] as usize;
+            if len == 0 {
+                offset = (offset + 1).next_multiple_of(BLOCK);
+                continue;
+            }
+            let r = &dir[offset..offset + len];
+            if &r[33..33 + r[32] as usize] == name.as_bytes() {
+                break r;
+            }
+            offset += len;
+        };
+    }
+    assert_eq!(record[25], 0);
+    let data = u32_at(record, 2) as usize * BLOCK;
+    assert_eq!(u32_at(record, 10), 5000);
+    assert_eq!(&image[data..data + 5000], &content[..]);
+
+    // the same file through the UDF file identifiers
+    let fsd = descriptor(PARTITION_LBA, 256, 0);
+    let mut icb = u32_at(fsd, 404);
+    for name in ["Repository", "stable", "main", "w", "wget_1.21_amd64.deb"] {
+        let fe = descriptor(PARTITION_LBA + icb, 261, icb);
+        assert_eq!(fe[27], 4);
+        let start = (PARTITION_LBA + u32_at(fe, 180)) as usize * BLOCK;
+        let dir = &image[start..start + u32_at(fe, 176) as usize];
+        let mut offset = 0;
+        icb = loop {
+            assert!(offset < dir.len(), "{} not found", name);
+            let fid = &dir[offset..];
+            assert_eq!(u16::from_le_bytes([fid[0], fid[1]]), 257);
+            let name_len = fid[19] as usize;
+            let mut id = fid[38..38 + name_len].to_vec();
+            id.push(name_len as u8);
+            if name_len > 0 && parse_dstring(&id) == name {
+                break u32_at(fid, 24);
+            }
+            offset += fid_len(name_len);
+        };
+    }
+    let fe = descriptor(PARTITION_LBA + icb, 261, icb);
+    assert_eq!(fe[27], 5);
+    assert_eq!(u64::from_le_bytes(fe[56..64].try_into()?), 5000);
+    assert_eq!(u32_at(fe, 176), 5000);
+    let data = (PARTITION_LBA + u32_at(fe, 180)) as usize * BLOCK;
+    assert_eq!(&image[data..data + 5000], &content[..]);
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! Write-ahead journal of a retirement.
+//!
+//! Every planned move is recorded before any file is touched, and every
+//! finished move right after it is done, one JSON object per line. Each
+//! line is flushed to the disk before moving on, so an interrupted
+//! retirement can always be resumed or rolled back from the journal.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::db::PackageMeta;
+
+pub const JOURNAL_NAME: &str = "retire.journal";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum JournalEntry {
+    /// Where the files are moved from and to
+    Begin {
+        pool: PathBuf,
+        output: PathBuf,
+        database: PathBuf,
+    },
+    /// The package is to be moved from the pool into the archive
+    Move { package: PackageMeta },
+    /// The package is already archived elsewhere, and is to be deleted
+    /// from the pool
+    Delete {
+        package: PackageMeta,
+        location: String,
+    },
+    /// The planned packages have been recorded in the archive database
+    Manifest,
+    /// The package has been moved or deleted
+    Done { filename: String },
+    /// The package has been put back into the pool
+    RolledBack { filename: String },
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl Journal {
+    /// Creates the journal, refusing to overwrite an existing one.
+    pub async fn create(path: &Path) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(path)
+            .await
+            .with_context(|| format!("when creating journal {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Opens an existing journal to append to it, returning its entries.
+    pub async fn open(path: &Path) -> Result<(Self, Vec<JournalEntry>)> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("when reading journal {}", path.display()))?;
+        let mut entries = Vec::new();
+        let mut valid_len = 0;
+        for (i, line) in content.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    valid_len += line.len() + 1;
+                }
+                // the last line may be cut short by a crash
+                Err(_) if i + 1 == content.lines().count() => break,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("when parsing line {} of the journal", i + 1))
+                }
+            }
+        }
+        if !matches!(entries.first(), Some(JournalEntry::Begin { .. })) {
+            bail!("{} is not a retirement journal", path.display());
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await?;
+        let journal = Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        };
+        if valid_len < content.len() {
+            // drop the line cut short
+            journal.file.lock().await.set_len(valid_len as u64).await?;
+        } else if valid_len > content.len() {
+            journal.file.lock().await.write_all(b"\n").await?;
+        }
+
+        Ok((journal, entries))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the entry, and waits until it reaches the disk.
+    pub async fn append(&self, entry: &JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await?;
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_journal() -> Result<()> {
+    let root = crate::testing::TempDir::new("journal")?;
+    let path = root.join(JOURNAL_NAME);
+    let begin = JournalEntry::Begin {
+        pool: "/mirror/debs".into(),
+        output: "/lookaside/public/archives/archive-20240101".into(),
+        database: "labels-20240101.db".into(),
+    };
+    let done = JournalEntry::Done {
+        filename: "pool/stable/main/w/webkit2gtk_2.20.0-0_amd64.deb".to_owned(),
+    };
+    let journal = Journal::create(&path).await?;
+    journal.append(&begin).await?;
+    journal.append(&done).await?;
+    let again = Journal::create(&path).await;
+    // a line cut short by a crash
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .await?;
+    file.write_all(b"{\"op\":\"do").await?;
+    // tokio writes in the background until flushed
+    file.flush().await?;
+    drop(file);
+    let (journal, entries) = Journal::open(&path).await?;
+    journal.append(&JournalEntry::Manifest).await?;
+    let (_, reopened) = Journal::open(&path).await?;
+    drop(root);
+    assert!(again.is_err());
+    assert_eq!(entries, vec![begin.clone(), done.clone()]);
+    assert_eq!(reopened, vec![begin, done, JournalEntry::Manifest]);
+    Ok(())
+}
@@ -0,0 +1,473 @@
+//! Which kernel versions to keep for each variant.
+//!
+//! ```toml
+//! [kernel]
+//! # newest versions of each variant to keep, none by default
+//! keep = 2
+//! # series whose newest version is kept, none by default
+//! keep_series = 2
+//! protect = ["6.1.*"]
+//!
+//! [[kernel.companions]]
+//! name = "*-{version}"
+//!
+//! [[kernel.companions]]
+//! version = "*+{version}"
+//! ```
+//!
+//! The versions the `linux+kernel[+variant]` metapackages point to are always
+//! kept. So are the `keep` newest versions of each variant in each
+//! repository, and the newest version of each of its `keep_series` newest
+//! series (like 6.1 or 6.6), so that the previous LTS series can stay as a
+//! fallback. Both are 0 unless configured, keeping only the current
+//! versions. Versions matching a `protect` pattern are never retired.
+//!
+//! Packages built for a retired kernel, like out-of-tree modules, are retired
+//! along with it. They are found with the `companions` rules, whose name and
+//! version globs get `{version}` replaced by the kernel version in the name
+//! of the kernel package, like `6.1.10` for `linux-kernel-lts-6.1.10`. They
+//! must be in the same repository, and no kernel of that version may be kept
+//! there.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::db::PackageMeta;
+use crate::version::{compare_versions, upstream_version};
+
+const KERNEL_PREFIX: &str = "linux-kernel-";
+const METAPACKAGE: &str = "linux+kernel";
+
+/// How to tell the packages built for a kernel version. A rule with both
+/// a name and a version matches only packages matching both.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompanionRule {
+    /// Shell-style glob of the package names, with `{version}` in it
+    pub name: Option<String>,
+    /// Shell-style glob of the package versions, with `{version}` in it
+    pub version: Option<String>,
+}
+
+fn default_companions() -> Vec<CompanionRule> {
+    let rule = |name: Option<&str>, version: Option<&str>| CompanionRule {
+        name: name.map(|n| n.to_owned()),
+        version: version.map(|v| v.to_owned()),
+    };
+
+    vec![
+        rule(Some("*-{version}"), None),
+        rule(Some("*-{version}-*"), None),
+        rule(None, Some("*+{version}")),
+        rule(None, Some("*+{version}-*")),
+    ]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KernelConfig {
+    /// Number of the newest versions to keep for each variant
+    #[serde(default)]
+    pub keep: usize,
+    /// Number of series to keep the newest version of
+    #[serde(default)]
+    pub keep_series: usize,
+    /// Shell-style globs of the versions never to retire
+    #[serde(default)]
+    pub protect: Vec<String>,
+    /// Rules finding the packages built for a kernel version
+    #[serde(default = "default_companions")]
+    pub companions: Vec<CompanionRule>,
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        Self {
+            keep: 0,
+            keep_series: 0,
+            protect: Vec::new(),
+            companions: default_companions(),
+        }
+    }
+}
+
+/// A kernel version of a variant in a repository, and what becomes of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelVersion {
+    /// The metapackage of the variant, like `linux+kernel+lts`
+    pub metapackage: String,
+    pub repo: String,
+    pub version: String,
+    pub retire: bool,
+    pub why: String,
+}
+
+/// A package built for a retired kernel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelCompanion {
+    /// The kernel package, like `linux-kernel-6.1.10`
+    pub kernel: String,
+    pub repo: String,
+    pub package: String,
+    pub version: String,
+    pub filename: String,
+}
+
+/// The kernel packages to retire, the versions of each variant, and the
+/// packages built for the retired kernels.
+#[derive(Debug, Default)]
+pub struct RetiredKernels {
+    pub packages: Vec<PackageMeta>,
+    pub versions: Vec<KernelVersion>,
+    pub companions: Vec<(PackageMeta, KernelCompanion)>,
+    /// The kernel packages kept
+    kept: Vec<PackageMeta>,
+}
+
+impl RetiredKernels {
+    /// The kernel versions in the names of the retired kernel packages.
+    pub fn retired_versions(&self) -> Vec<String> {
+        let versions = self
+            .packages
+            .iter()
+            .filter_map(|p| kernel_version(&p.package))
+            .map(|v| v.to_owned())
+            .collect::<HashSet<_>>();
+
+        versions.into_iter().collect()
+    }
+}
+
+/// The kernel version in the name of a kernel package, like `6.1.10` for
+/// `linux-kernel-lts-6.1.10`.
+fn kernel_version(package: &str) -> Option<&str> {
+    let rest = package.strip_prefix(KERNEL_PREFIX)?;
+    let start = rest
+        .char_indices()
+        .find(|(i, c)| c.is_ascii_digit() && (*i == 0 || rest[..*i].ends_with('-')))?;
+
+    Some(&rest[start.0..])
+}
+
+/// The series of a kernel version, like `6.1` for `6.1.10-0`.
+fn series(version: &str) -> String {
+    upstream_version(version)
+        .split('.')
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Finds the metapackage of the variant a kernel package belongs to, like
+/// `linux+kernel+lts` for `linux-kernel-lts-6.1.10`, among the known ones.
+fn metapackage_of<'a>(package: &str, metapackages: &[&'a str]) -> Option<&'a str> {
+    let rest = package.strip_prefix(KERNEL_PREFIX)?;
+    let is_version = |s: &str| s.starts_with(|c: char| c.is_ascii_digit());
+    metapackages
+        .iter()
+        .filter(|m| match m.strip_prefix(METAPACKAGE) {
+            Some("") => is_version(rest),
+            Some(variant) => variant
+                .strip_prefix('+')
+                .and_then(|v| rest.strip_prefix(v))
+                .and_then(|r| r.strip_prefix('-'))
+                .is_some_and(is_version),
+            None => false,
+        })
+        .max_by_key(|m| m.len())
+        .copied()
+}
+
+/// Fills in the kernel version in a companion pattern.
+fn companion_pattern(pattern: &str, version: &str) -> Result<glob::Pattern> {
+    glob::Pattern::new(&pattern.replace("{version}", &glob::Pattern::escape(version)))
+        .with_context(|| format!("invalid companion pattern {}", pattern))
+}
+
+pub struct KernelPolicy {
+    config: KernelConfig,
+    protect: Vec<glob::Pattern>,
+}
+
+impl KernelPolicy {
+    pub fn new(config: KernelConfig) -> Result<Self> {
+        let protect = config
+            .protect
+            .iter()
+            .map(|p| glob::Pattern::new(p).with_context(|| format!("invalid kernel pattern {}", p)))
+            .collect::<Result<_>>()?;
+        for rule in config.companions.iter() {
+            if rule.name.is_none() && rule.version.is_none() {
+                bail!("A companion rule needs a name or a version");
+            }
+            for pattern in rule.name.iter().chain(rule.version.iter()) {
+                companion_pattern(pattern, "0")?;
+            }
+        }
+
+        Ok(Self { config, protect })
+    }
+
+    /// Tells why the version is kept, if it is. `index` is its position
+    /// among the versions of the variant, the newest being 0, and
+    /// `series_index` that of its series, if it is the newest of it.
+    fn keep(
+        &self,
+        version: &str,
+        current: &[&str],
+        index: usize,
+        series_index: Option<usize>,
+    ) -> Option<String> {
+        if current
+            .iter()
+            .any(|v| compare_versions(upstream_version(v), upstream_version(version)).is_eq())
+        {
+            return Some("current version of the metapackage".to_owned());
+        }
+        if let Some(pattern) = self.protect.iter().find(|p| p.matches(version)) {
+            return Some(format!("protected by {}", pattern));
+        }
+        if index < self.config.keep {
+            return Some(format!("one of the {} newest", self.config.keep));
+        }
+        if series_index.is_some_and(|i| i < self.config.keep_series) {
+            return Some(format!("newest of the {} series", series(version)));
+        }
+
+        None
+    }
+
+    /// Decides which kernel packages to retire, given the metapackages of
+    /// the variants. Kernels of variants without a metapackage are left
+    /// alone.
+    pub fn select(
+        &self,
+        kernels: Vec<PackageMeta>,
+        metapackages: &[PackageMeta],
+    ) -> RetiredKernels {
+        let mut current: HashMap<&str, Vec<&str>> = HashMap::new();
+        for m in metapackages {
+            current.entry(&m.package).or_default().push(&m.version);
+        }
+        let names = current.keys().copied().collect::<Vec<_>>();
+        let mut groups: BTreeMap<(&str, String), Vec<PackageMeta>> = BTreeMap::new();
+        for p in kernels {
+            match metapackage_of(&p.package, &names) {
+                Some(m) => groups.entry((m, p.repo.clone())).or_default().push(p),
+                None => debug!("{} belongs to no known kernel variant", p.package),
+            }
+        }
+        let mut retired = RetiredKernels::default();
+        for ((metapackage, repo), packages) in groups {
+            let mut versions = packages
+                .iter()
+                .map(|p| p.version.as_str())
+                .collect::<Vec<_>>();
+            versions.sort_by(|a, b| compare_versions(b, a));
+            versions.dedup();
+            let mut newest_of_series: Vec<(String, &str)> = Vec::new();
+            for v in versions.iter() {
+                let s = series(v);
+                if !newest_of_series.iter().any(|(known, _)| *known == s) {
+                    newest_of_series.push((s, v));
+                }
+            }
+            for (index, version) in versions.iter().enumerate() {
+                let series_index = newest_of_series.iter().position(|(_, v)| v == version);
+                let why = self.keep(version, &current[metapackage], index, series_index);
+                let same_version = packages.iter().filter(|p| p.version == *version).cloned();
+                if why.is_none() {
+                    retired.packages.extend(same_version);
+                } else {
+                    retired.kept.extend(same_version);
+                }
+                retired.versions.push(KernelVersion {
+                    metapackage: metapackage.to_owned(),
+                    repo: repo.clone(),
+                    version: version.to_string(),
+                    retire: why.is_none(),
+                    why: why.unwrap_or_else(|| "older than the kept versions".to_owned()),
+                });
+            }
+        }
+
+        retired
+    }
+
+    /// Adds the packages built for the retired kernels among `packages`,
+    /// leaving out those whose kernel version is still kept in the
+    /// repository.
+    pub fn add_companions(
+        &self,
+        retired: &mut RetiredKernels,
+        packages: Vec<PackageMeta>,
+    ) -> Result<()> {
+        let kept = retired
+            .kept
+            .iter()
+            .filter_map(|k| Some((k.repo.as_str(), kernel_version(&k.package)?)))
+            .collect::<HashSet<_>>();
+        let mut kernels = Vec::new();
+        for k in retired.packages.iter() {
+            let Some(version) = kernel_version(&k.package) else {
+                continue;
+            };
+            if kept.contains(&(k.repo.as_str(), version)) {
+                continue;
+            }
+            let pattern = |p: &Option<String>| {
+                p.as_deref()
+                    .map(|p| companion_pattern(p, version))
+                    .transpose()
+            };
+            let rules = self
+                .config
+                .companions
+                .iter()
+                .map(|r| Ok((pattern(&r.name)?, pattern(&r.version)?)))
+                .collect::<Result<Vec<_>>>()?;
+            kernels.push((k, rules));
+        }
+        let kernel_files = retired
+            .packages
+            .iter()
+            .chain(retired.kept.iter())
+            .map(|k| k.filename.as_str())
+            .collect::<HashSet<_>>();
+        let mut companions = Vec::new();
+        for p in packages {
+            if kernel_files.contains(p.filename.as_str()) || p.package.starts_with(METAPACKAGE) {
+                continue;
+            }
+            let kernel = kernels.iter().find(|(k, rules)| {
+                k.repo == p.repo
+                    && rules.iter().any(|(name, version)| {
+                        name.as_ref().is_none_or(|n| n.matches(&p.package))
+                            && version.as_ref().is_none_or(|v| v.matches(&p.version))
+                    })
+            });
+            if let Some((k, _)) = kernel {
+                companions.push((
+                    p.clone(),
+                    KernelCompanion {
+                        kernel: k.package.clone(),
+                        repo: p.repo.clone(),
+                        package: p.package.clone(),
+                        version: p.version.clone(),
+                        filename: p.filename.clone(),
+                    },
+                ));
+            }
+        }
+        companions.sort_by(|(_, a), (_, b)| {
+            (&a.kernel, &a.repo, &a.package).cmp(&(&b.kernel, &b.repo, &b.package))
+        });
+        retired.companions = companions;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_kernel_policy() -> Result<()> {
+    let package = |name: &str, version: &str| crate::testing::package(name, version, "amd64");
+    let config: KernelConfig = toml::from_str("keep = 1\nkeep_series = 2\nprotect = [\"5.15.*\"]")?;
+    let policy = KernelPolicy::new(config)?;
+    let metapackages = vec![
+        package("linux+kernel", "1:6.6.10-1"),
+        package("linux+kernel+lts", "6.1.20"),
+        package("linux+kernel+lts-rc", "6.2~rc1"),
+    ];
+    let kernels = vec![
+        package("linux-kernel-6.6.10", "6.6.10-0"),
+        package("linux-kernel-6.6.11", "6.6.11-0"),
+        package("linux-kernel-6.6.9", "6.6.9-0"),
+        package("linux-kernel-6.1.70", "6.1.70-0"),
+        package("linux-kernel-6.1.69", "6.1.69-0"),
+        package("linux-kernel-5.15.1", "5.15.1-0"),
+        package("linux-kernel-5.10.1", "5.10.1-0"),
+        package("linux-kernel-lts-6.1.20", "6.1.20-0"),
+        package("linux-kernel-lts-6.1.19", "6.1.19-0"),
+        package("linux-kernel-lts-rc-6.2~rc1", "6.2~rc1"),
+        package("linux-kernel-gone-5.4.1", "5.4.1-0"),
+    ];
+    let retired = policy.select(kernels, &metapackages);
+    let mut retired_packages = retired
+        .packages
+        .iter()
+        .map(|p| p.package.as_str())
+        .collect::<Vec<_>>();
+    retired_packages.sort_unstable();
+    assert_eq!(
+        retired_packages,
+        vec![
+            "linux-kernel-5.10.1",
+            "linux-kernel-6.1.69",
+            "linux-kernel-6.6.9",
+            "linux-kernel-lts-6.1.19",
+        ]
+    );
+    let versions = retired
+        .versions
+        .iter()
+        .map(|v| format!("{} {}: {}", v.metapackage, v.version, v.why))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        versions,
+        vec![
+            "linux+kernel 6.6.11-0: one of the 1 newest",
+            "linux+kernel 6.6.10-0: current version of the metapackage",
+            "linux+kernel 6.6.9-0: older than the kept versions",
+            "linux+kernel 6.1.70-0: newest of the 6.1 series",
+            "linux+kernel 6.1.69-0: older than the kept versions",
+            "linux+kernel 5.15.1-0: protected by 5.15.*",
+            "linux+kernel 5.10.1-0: older than the kept versions",
+            "linux+kernel+lts 6.1.20-0: current version of the metapackage",
+            "linux+kernel+lts 6.1.19-0: older than the kept versions",
+            "linux+kernel+lts-rc 6.2~rc1: current version of the metapackage",
+        ]
+    );
+    let mut retired = retired;
+    let mut lts = package("linux-kernel-lts-6.1.69", "6.1.69-0");
+    lts.repo = "amd64/lts".to_owned();
+    retired.kept.push(lts);
+    policy.add_companions(
+        &mut retired,
+        vec![
+            package("rtl8821ce-6.6.9", "1.0-0"),
+            package("linux-kernel-headers-6.1.69", "6.1.69-0"),
+            package("nvidia-kmod", "535.1+6.1.69-0"),
+            package("nvidia-kmod", "535.1+6.1.699-0"),
+            package("perf-6.6.90", "6.6.90-0"),
+            package("zfs-kmod", "2.2.0+6.6.10"),
+            package("zfs-kmod", "2.2.0+6.1.19"),
+        ],
+    )?;
+    let companions = retired
+        .companions
+        .iter()
+        .map(|(_, c)| format!("{}: {} {}", c.kernel, c.package, c.version))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        companions,
+        vec![
+            "linux-kernel-6.1.69: linux-kernel-headers-6.1.69 6.1.69-0",
+            "linux-kernel-6.1.69: nvidia-kmod 535.1+6.1.69-0",
+            "linux-kernel-6.6.9: rtl8821ce-6.6.9 1.0-0",
+            "linux-kernel-lts-6.1.19: zfs-kmod 2.2.0+6.1.19",
+        ]
+    );
+    assert_eq!(
+        kernel_version("linux-kernel-lts-rc-6.2~rc1"),
+        Some("6.2~rc1")
+    );
+    // only the current versions are kept unless configured
+    let unset: KernelConfig = toml::from_str("")?;
+    assert_eq!((unset.keep, unset.keep_series), (0, 0));
+    let invalid: KernelConfig = toml::from_str("[[companions]]\nname = \"[{version}\"")?;
+    assert!(KernelPolicy::new(invalid).is_err());
+    Ok(())
+}
@@ -1,18 +1,46 @@
 use anyhow::Result;
 
 mod abbs;
+mod binning;
+mod catalog;
 mod cli;
 mod db;
 mod dbus;
+mod dedup;
+mod depends;
+mod image;
+mod journal;
+mod kernel;
+mod manifest;
+mod plan;
+mod protect;
+mod report;
+mod restore;
+mod retention;
 mod retire;
+mod rollback;
+mod run;
+#[cfg(test)]
+mod testing;
+mod version;
 
+use binning::binning_action;
+use catalog::{import_discs_action, search_action};
 use clap::Parser;
+use dedup::dedup_report_action;
+use image::mkimage_action;
+use restore::restore_action;
 use retire::retire_action;
+use rollback::rollback_action;
+use run::run_action;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = cli::Args::parse();
-    env_logger::init();
+    // `run` sends the log to its own log file
+    if !matches!(args, cli::Args::Run(_)) {
+        env_logger::init();
+    }
 
     match args {
         cli::Args::Retire(args) => {
@@ -24,22 +52,57 @@ async fn main() -> Result<()> {
                 inhibited = Some(dbus::inhibit_services(&conn, &args.inhibit).await.unwrap());
             }
 
-            retire_action(
-                args.config,
-                args.dry_run,
-                args.output,
-                args.out_of_tree,
-                args.with_kernel,
-                args.database,
-                args.abbs_dir,
-            )
-            .await?;
+            retire_action(&args).await?;
             // restore services
             if let Some(inhibit) = inhibited {
                 dbus::restore_services(&inhibit).await?;
             }
         }
-        cli::Args::Binning(_) => todo!(),
+        cli::Args::Binning(args) => {
+            binning_action(
+                args.input,
+                args.output,
+                &args.size,
+                args.start,
+                &args.labels,
+                &args.project,
+            )?;
+        }
+        cli::Args::MkImage(args) => {
+            mkimage_action(args.input, args.output)?;
+        }
+        cli::Args::ImportDiscs(args) => {
+            import_discs_action(args.input, args.database)?;
+        }
+        cli::Args::Search(args) => {
+            search_action(
+                &args.name,
+                args.version.as_deref(),
+                args.arch.as_deref(),
+                &args.labels,
+                args.database.as_deref(),
+                args.json,
+            )?;
+        }
+        cli::Args::DedupReport(args) => {
+            dedup_report_action(
+                args.input.as_deref(),
+                args.database.as_deref(),
+                &args.labels,
+                args.volumes.as_deref(),
+                args.output.as_deref(),
+                args.json,
+            )?;
+        }
+        cli::Args::Restore(args) => {
+            restore_action(&args).await?;
+        }
+        cli::Args::Rollback(args) => {
+            rollback_action(&args).await?;
+        }
+        cli::Args::Run(args) => {
+            run_action(&args).await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,273 @@
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+use crate::binning::SECTOR_SIZE;
+
+// The same glyphs tree(1) prints in a UTF-8 locale.
+const BRANCH: &str = "├── ";
+const LAST_BRANCH: &str = "└── ";
+const INDENT: &str = "│\u{a0}\u{a0} ";
+const LAST_INDENT: &str = "    ";
+
+/// Space to set aside on every volume for the manifests, besides what
+/// [`manifest_cost`] accounts for each file: the rounding of both
+/// manifests to whole sectors, and their headers and footers.
+pub const MANIFEST_RESERVE: u64 = 3 * SECTOR_SIZE;
+
+pub fn tree_name(number: usize) -> String {
+    format!("disc-{}.tree", number)
+}
+
+pub fn md5_name(number: usize) -> String {
+    format!("disc-{}.md5", number)
+}
+
+/// Upper bound of the bytes a file takes in the manifests of a volume.
+/// `path` is relative to the root of the volume.
+pub fn manifest_cost(path: &Path) -> u64 {
+    let len = path.as_os_str().len() as u64;
+    let depth = path.components().count() as u64;
+    let md5_line = 32 + 2 + 2 + len + 1;
+    // assume that every parent directory is listed for this file alone
+    let indent = (INDENT.len() as u64) * depth * (depth - 1) / 2;
+    let tree_lines = indent + depth * (BRANCH.len() as u64 + 1) + len;
+
+    md5_line + tree_lines
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<OsString>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        names.push(entry?.file_name());
+    }
+    names.sort_unstable();
+
+    Ok(names)
+}
+
+fn render_dir(
+    dir: &Path,
+    names: Vec<OsString>,
+    prefix: &str,
+    out: &mut String,
+    counts: &mut (usize, usize),
+) -> Result<()> {
+    let total = names.len();
+    for (i, name) in names.into_iter().enumerate() {
+        let last = i + 1 == total;
+        let path = dir.join(&name);
+        out.push_str(prefix);
+        out.push_str(if last { LAST_BRANCH } else { BRANCH });
+        // tree(1) escapes spaces in file names
+        out.push_str(&name.to_string_lossy().replace(' ', "\\ "));
+        out.push('\n');
+        if path.is_dir() && !path.is_symlink() {
+            counts.0 += 1;
+            let prefix = format!("{}{}", prefix, if last { LAST_INDENT } else { INDENT });
+            render_dir(&path, sorted_entries(&path)?, &prefix, out, counts)?;
+        } else {
+            counts.1 += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders the volume in the same format as `tree(1)` run in its root,
+/// including the manifests themselves.
+pub fn render_tree(volume_dir: &Path, number: usize) -> Result<String> {
+    let manifests = [
+        OsString::from(md5_name(number)),
+        OsString::from(tree_name(number)),
+    ];
+    let mut names = sorted_entries(volume_dir)?;
+    names.retain(|n| !manifests.contains(n));
+    names.extend(manifests);
+    names.sort_unstable();
+    let mut out = String::from(".\n");
+    let mut counts = (0, 0);
+    render_dir(volume_dir, names, "", &mut out, &mut counts)?;
+    let (dirs, files) = counts;
+    writeln!(
+        out,
+        "\n{} director{}, {} file{}",
+        dirs,
+        if dirs == 1 { "y" } else { "ies" },
+        files,
+        if files == 1 { "" } else { "s" }
+    )?;
+
+    Ok(out)
+}
+
+pub fn md5_file(path: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Md5::new();
+    std::io::copy(&mut f, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes `disc-N.tree` and `disc-N.md5` into the root of the volume.
+/// The checksum list covers every file on the volume and the tree, but
+/// never the checksum list itself.
+pub fn write_manifests(volume_dir: &Path, number: usize) -> Result<()> {
+    let tree = render_tree(volume_dir, number)?;
+    let mut md5 = String::new();
+    let walker = walkdir::WalkDir::new(volume_dir)
+        .min_depth(1)
+        .sort_by_file_name();
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(volume_dir)?;
+        if path == Path::new(&md5_name(number)) || path == Path::new(&tree_name(number)) {
+            continue;
+        }
+        let sum = md5_file(entry.path())
+            .with_context(|| format!("when hashing {}", entry.path().display()))?;
+        writeln!(md5, "{}  ./{}", sum, path.display())?;
+    }
+    writeln!(
+        md5,
+        "{:x}  ./{}",
+        Md5::digest(tree.as_bytes()),
+        tree_name(number)
+    )?;
+    std::fs::write(volume_dir.join(tree_name(number)), tree)?;
+    std::fs::write(volume_dir.join(md5_name(number)), md5)?;
+
+    Ok(())
+}
+
+/// Entries of a tree listing, with paths relative to the root of the disc.
+#[derive(Debug, Default)]
+pub struct TreeListing {
+    /// Leaf entries, which are files or empty directories
+    pub leaves: Vec<String>,
+    /// Number of directories that are not leaves
+    pub dirs: usize,
+    /// Directory and file counts from the last line
+    pub summary: Option<(usize, usize)>,
+}
+
+pub fn parse_tree(content: &str) -> TreeListing {
+    let mut listing = TreeListing::default();
+    let mut entries: Vec<(usize, String)> = Vec::new();
+    let mut stack: Vec<String> = Vec::new();
+    for line in content.lines().skip(1) {
+        let Some((prefix, name)) = line
+            .split_once(BRANCH)
+            .or_else(|| line.split_once(LAST_BRANCH))
+        else {
+            if let Some((dirs, files)) = line.split_once(", ") {
+                let count = |s: &str| s.split(' ').next().and_then(|n| n.parse().ok());
+                if let (Some(dirs), Some(files)) = (count(dirs), count(files)) {
+                    listing.summary = Some((dirs, files));
+                }
+            }
+            continue;
+        };
+        let depth = prefix.chars().count() / INDENT.chars().count();
+        stack.truncate(depth);
+        stack.push(name.replace("\\ ", " "));
+        entries.push((depth, stack.join("/")));
+    }
+    for (i, (depth, path)) in entries.iter().enumerate() {
+        match entries.get(i + 1) {
+            Some((next, _)) if next > depth => listing.dirs += 1,
+            _ => listing.leaves.push(path.clone()),
+        }
+    }
+
+    listing
+}
+
+/// Parses the lines of a checksum list into pairs of checksums and paths
+/// relative to the root of the disc.
+pub fn parse_md5(content: &str) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let Some((sum, path)) = line.split_once("  ./") else {
+            bail!("Malformed checksum line {}: {}", i + 1, line);
+        };
+        if sum.len() != 32 || !sum.bytes().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Malformed checksum line {}: {}", i + 1, line);
+        }
+        entries.push((sum.to_owned(), path.to_owned()));
+    }
+
+    Ok(entries)
+}
+
+#[test]
+fn test_write_manifests() -> Result<()> {
+    let volume_dir = crate::testing::TempDir::new("manifest")?;
+    let pool = volume_dir.join("Repository/stable/main");
+    std::fs::create_dir_all(pool.join("a"))?;
+    std::fs::create_dir_all(pool.join("b"))?;
+    std::fs::write(pool.join("a/a_1_amd64.deb"), "a")?;
+    std::fs::write(pool.join("a/a+b_1_amd64.deb"), "")?;
+    std::fs::write(pool.join("b/b_1_amd64.deb"), "b")?;
+    std::fs::write(pool.join("b/b c.tar.xz"), "b c")?;
+    write_manifests(&volume_dir, 49)?;
+    let tree = std::fs::read_to_string(volume_dir.join("disc-49.tree"))?;
+    let md5 = std::fs::read_to_string(volume_dir.join("disc-49.md5"))?;
+    drop(volume_dir);
+    assert_eq!(
+        tree,
+        ".
+├── Repository
+│\u{a0}\u{a0} └── stable
+│\u{a0}\u{a0}     └── main
+│\u{a0}\u{a0}         ├── a
+│\u{a0}\u{a0}         │\u{a0}\u{a0} ├── a+b_1_amd64.deb
+│\u{a0}\u{a0}         │\u{a0}\u{a0} └── a_1_amd64.deb
+│\u{a0}\u{a0}         └── b
+│\u{a0}\u{a0}             ├── b\\ c.tar.xz
+│\u{a0}\u{a0}             └── b_1_amd64.deb
+├── disc-49.md5
+└── disc-49.tree
+
+5 directories, 6 files
+"
+    );
+    let lines = md5.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(
+        lines[0],
+        "d41d8cd98f00b204e9800998ecf8427e  ./Repository/stable/main/a/a+b_1_amd64.deb"
+    );
+    assert_eq!(
+        lines[4],
+        format!("{:x}  ./disc-49.tree", Md5::digest(tree.as_bytes()))
+    );
+    assert!(!md5.contains("disc-49.md5"));
+    let listing = parse_tree(&tree);
+    assert_eq!(listing.summary, Some((5, 6)));
+    assert_eq!(listing.dirs, 5);
+    assert!(listing
+        .leaves
+        .contains(&"Repository/stable/main/b/b c.tar.xz".to_owned()));
+    let entries = parse_md5(&md5)?;
+    assert_eq!(entries.len(), 5);
+    for (_, path) in entries {
+        assert!(listing.leaves.contains(&path));
+    }
+    Ok(())
+}
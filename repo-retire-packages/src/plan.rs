@@ -0,0 +1,267 @@
+//! Retirement plans saved by a dry run.
+//!
+//! A plan records exactly which packages the dry run would retire, along
+//! with a checksum of its contents. The real run carries out only the
+//! packages in the plan, after checking each of them against the live
+//! database, so what was reviewed is what gets retired.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::{PackageMeta, Reason, TopicStatus};
+use crate::kernel::{KernelCompanion, KernelVersion};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub out_of_tree: bool,
+    pub with_kernel: bool,
+    #[serde(default)]
+    pub topics: bool,
+    #[serde(default)]
+    pub orphaned_dbg: bool,
+    /// Packages to move into the archive
+    pub packages: Vec<PackageMeta>,
+    /// Packages already archived elsewhere, to delete from the pool
+    pub archived: Vec<(PackageMeta, String)>,
+    /// Why each package is retired, keyed by its file name
+    pub reasons: BTreeMap<String, Reason>,
+    /// The retention rules applied to the superseded packages
+    #[serde(default)]
+    pub rules: BTreeMap<String, String>,
+    /// Candidates kept for now, and why
+    #[serde(default)]
+    pub kept: Vec<(PackageMeta, String)>,
+    /// Packages on the protect-list, and the entries protecting them
+    #[serde(default)]
+    pub protected: Vec<(PackageMeta, String)>,
+    /// Topics not fully merged into stable, left for a human to decide
+    #[serde(default)]
+    pub unmerged_topics: Vec<TopicStatus>,
+    /// The kernel versions of each variant, and which are retired
+    #[serde(default)]
+    pub kernels: Vec<KernelVersion>,
+    /// The packages built for the retired kernels
+    #[serde(default)]
+    pub kernel_companions: Vec<KernelCompanion>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PlanFile {
+    sha256: String,
+    plan: Plan,
+}
+
+impl Plan {
+    /// Describes why the package is retired, e.g. `superseded (default: keep 1)`.
+    pub fn describe(&self, filename: &str) -> String {
+        let reason = self.reasons[filename].to_string();
+        match self.rules.get(filename) {
+            Some(rule) => format!("{} ({})", reason, rule),
+            None => reason,
+        }
+    }
+
+    pub fn checksum(&self) -> Result<String> {
+        Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(self)?)))
+    }
+
+    /// Saves the plan along with its checksum, returning the checksum.
+    pub fn save(&self, path: &Path) -> Result<String> {
+        let sha256 = self.checksum()?;
+        let file = PlanFile {
+            sha256: sha256.clone(),
+            plan: self.clone(),
+        };
+        std::fs::write(path, serde_json::to_vec_pretty(&file)?)
+            .with_context(|| format!("when writing {}", path.display()))?;
+
+        Ok(sha256)
+    }
+
+    /// Loads the plan, refusing it if it was changed after it was saved.
+    pub fn load(path: &Path) -> Result<(Self, String)> {
+        let content =
+            std::fs::read(path).with_context(|| format!("when reading {}", path.display()))?;
+        let file: PlanFile = serde_json::from_slice(&content)
+            .with_context(|| format!("when parsing {}", path.display()))?;
+        let actual = file.plan.checksum()?;
+        if actual != file.sha256 {
+            bail!(
+                "{} was modified after it was saved: expected checksum {}, got {}",
+                path.display(),
+                file.sha256,
+                actual
+            );
+        }
+
+        Ok((file.plan, actual))
+    }
+
+    /// Checks the plan against the packages to retire now, and `known`, the
+    /// packages with the planned file names still in the database, returning
+    /// the part of the plan that can be carried out.
+    ///
+    /// Packages removed from the database since are left out of the plan,
+    /// and so are packages which became retired since. Any other difference
+    /// is an error.
+    pub fn check(&self, retired: &[(PackageMeta, Reason)], known: &[PackageMeta]) -> Result<Self> {
+        let retired = retired
+            .iter()
+            .map(|(p, _)| (p.filename.as_str(), p))
+            .collect::<HashMap<_, _>>();
+        let known = known
+            .iter()
+            .map(|p| p.filename.as_str())
+            .collect::<HashSet<_>>();
+        let mut checked = Self {
+            out_of_tree: self.out_of_tree,
+            with_kernel: self.with_kernel,
+            topics: self.topics,
+            orphaned_dbg: self.orphaned_dbg,
+            kept: self.kept.clone(),
+            protected: self.protected.clone(),
+            ..Default::default()
+        };
+        let mut errors = 0;
+        let planned = self
+            .packages
+            .iter()
+            .map(|p| (p, None))
+            .chain(self.archived.iter().map(|(p, l)| (p, Some(l))));
+        for (p, location) in planned {
+            match retired.get(p.filename.as_str()) {
+                Some(live) if *live == p => (),
+                Some(live) => {
+                    error!(
+                        "{} changed since the plan was made: {} {} ({}), now {} {} ({})",
+                        p.filename,
+                        p.package,
+                        p.version,
+                        p.sha256,
+                        live.package,
+                        live.version,
+                        live.sha256
+                    );
+                    errors += 1;
+                    continue;
+                }
+                None if known.contains(p.filename.as_str()) => {
+                    error!("{} is no longer to be retired", p.filename);
+                    errors += 1;
+                    continue;
+                }
+                None => {
+                    warn!("Skipping, no longer in the repository: {}", p.filename);
+                    continue;
+                }
+            }
+            checked
+                .reasons
+                .insert(p.filename.clone(), self.reasons[&p.filename]);
+            if let Some(rule) = self.rules.get(&p.filename) {
+                checked.rules.insert(p.filename.clone(), rule.clone());
+            }
+            match location {
+                // the copy on a disc cannot be checked here
+                Some(location)
+                    if !location.starts_with("disc-") && !Path::new(location).is_file() =>
+                {
+                    error!("{} is no longer archived at {}", p.filename, location);
+                    errors += 1;
+                }
+                Some(location) => checked.archived.push((p.clone(), location.clone())),
+                None => checked.packages.push(p.clone()),
+            }
+        }
+        if errors > 0 {
+            bail!(
+                "{} packages in the plan differ from the database, make a new plan",
+                errors
+            );
+        }
+        let unplanned = retired
+            .keys()
+            .filter(|f| !self.reasons.contains_key(**f))
+            .count();
+        if unplanned > 0 {
+            info!(
+                "{} packages to retire are not in the plan, leaving them for the next retirement",
+                unplanned
+            );
+        }
+
+        Ok(checked)
+    }
+}
+
+#[test]
+fn test_plan() -> Result<()> {
+    let package = |name: &str, version: &str| PackageMeta {
+        sha256: format!("{:x}", Sha256::digest(format!("{}{}", name, version))),
+        size: 10,
+        ..crate::testing::package(name, version, "amd64")
+    };
+    let (webkit, wget, gone) = (
+        package("webkit2gtk", "2.20.0-0"),
+        package("wget", "1.0-0"),
+        package("wayland", "1.0-0"),
+    );
+    let plan = Plan {
+        out_of_tree: false,
+        with_kernel: false,
+        topics: false,
+        orphaned_dbg: false,
+        packages: vec![webkit.clone(), gone.clone()],
+        archived: vec![(wget.clone(), "disc-3:./x".to_owned())],
+        reasons: [&webkit, &wget, &gone]
+            .iter()
+            .map(|p| (p.filename.clone(), Reason::Superseded))
+            .collect(),
+        rules: [(webkit.filename.clone(), "default: keep 1".to_owned())].into(),
+        kept: Vec::new(),
+        protected: Vec::new(),
+        unmerged_topics: Vec::new(),
+        kernels: Vec::new(),
+        kernel_companions: Vec::new(),
+    };
+    let root = crate::testing::TempDir::new("plan")?;
+    let path = root.join("plan.json");
+    let sha256 = plan.save(&path)?;
+    let (loaded, loaded_sha256) = Plan::load(&path)?;
+    let tampered = std::fs::read_to_string(&path)?.replace("wayland", "weston");
+    std::fs::write(&path, tampered)?;
+    let tampered = Plan::load(&path);
+    drop(root);
+    assert_eq!(loaded, plan);
+    assert_eq!(loaded_sha256, sha256);
+    assert!(tampered.is_err());
+
+    // wayland is gone from the database, and a newer package became retired
+    let newer = package("webkit2gtk", "2.22.0-0");
+    let retired = [&webkit, &wget, &newer].map(|p| (p.clone(), Reason::Superseded));
+    let checked = plan.check(&retired, &[webkit.clone(), wget.clone()])?;
+    assert_eq!(checked.packages, vec![webkit.clone()]);
+    assert_eq!(checked.archived.len(), 1);
+    assert_eq!(checked.reasons.len(), 2);
+    assert_eq!(
+        checked.describe(&webkit.filename),
+        "superseded (default: keep 1)"
+    );
+    // webkit2gtk is the latest version again
+    assert!(plan
+        .check(&retired[1..], &[webkit.clone(), wget.clone()])
+        .is_err());
+    // webkit2gtk was rebuilt
+    let mut rebuilt = webkit.clone();
+    rebuilt.sha256 = "0".repeat(64);
+    let retired = [&rebuilt, &wget].map(|p| (p.clone(), Reason::Superseded));
+    assert!(plan
+        .check(&retired, &[rebuilt.clone(), wget.clone()])
+        .is_err());
+    Ok(())
+}
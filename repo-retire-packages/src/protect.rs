@@ -0,0 +1,262 @@
+//! Packages that must never be retired, whatever the reason.
+//!
+//! ```toml
+//! [[protect]]
+//! package = "u-boot-*"
+//! reason = "Needed to recover boards with broken boot loaders"
+//!
+//! [[protect]]
+//! package = "glibc"
+//! version = "2.36-*"
+//! architecture = "loongson3"
+//! reason = "Last version booting on the old firmware"
+//! expires = 2025-06-30
+//! ```
+//!
+//! The package, version and architecture are shell-style globs, so exact
+//! values match only themselves. An entry stops protecting packages after
+//! its expiry date, and one with `only_for` protects them only when they
+//! are retired for one of these reasons.
+//!
+//! The boot loaders, the -dbg packages and the kernels are not in the ABBS
+//! tree under their package names, so they are protected from out-of-tree
+//! retirement by default entries following those of the protect-list.
+//! `--no-default-protect` leaves them out.
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use log::info;
+use serde::Deserialize;
+
+use crate::db::{PackageMeta, Reason};
+
+/// Packages protected unless `--no-default-protect` is given, all of which
+/// would look out of tree.
+const DEFAULT_PROTECT: &str = r#"
+[[protect]]
+package = "u-boot*"
+only_for = ["out-of-tree"]
+reason = "Needed to recover boards with broken boot loaders"
+
+[[protect]]
+package = "*-dbg"
+only_for = ["out-of-tree"]
+reason = "Debug symbols follow their base packages, see --orphaned-dbg"
+
+[[protect]]
+package = "linux-kernel-*"
+only_for = ["out-of-tree"]
+reason = "Kernels follow the kernel policy, see --with-kernel"
+
+[[protect]]
+package = "linux+kernel+*"
+only_for = ["out-of-tree"]
+reason = "Kernels follow the kernel policy, see --with-kernel"
+"#;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtectEntry {
+    pub package: String,
+    pub version: Option<String>,
+    pub architecture: Option<String>,
+    /// Only protect the packages retired for these reasons
+    pub only_for: Option<Vec<Reason>>,
+    pub reason: String,
+    pub expires: Option<toml::value::Datetime>,
+}
+
+impl fmt::Display for ProtectEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.package)?;
+        if let Some(version) = &self.version {
+            write!(f, " {}", version)?;
+        }
+        if let Some(architecture) = &self.architecture {
+            write!(f, " [{}]", architecture)?;
+        }
+        if let Some(only_for) = &self.only_for {
+            let reasons = only_for.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+            write!(f, " ({} only)", reasons.join(", "))?;
+        }
+        write!(f, ": {}", self.reason)?;
+        if let Some(expires) = &self.expires {
+            write!(f, " (until {})", expires)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct ProtectFile {
+    #[serde(default)]
+    protect: Vec<ProtectEntry>,
+}
+
+struct Matcher {
+    entry: ProtectEntry,
+    package: glob::Pattern,
+    version: Option<glob::Pattern>,
+    architecture: Option<glob::Pattern>,
+}
+
+impl Matcher {
+    fn new(entry: ProtectEntry) -> Result<Self> {
+        let pattern = |p: &str| {
+            glob::Pattern::new(p).with_context(|| format!("invalid pattern {} in {}", p, entry))
+        };
+
+        Ok(Self {
+            package: pattern(&entry.package)?,
+            version: entry.version.as_deref().map(pattern).transpose()?,
+            architecture: entry.architecture.as_deref().map(pattern).transpose()?,
+            entry,
+        })
+    }
+
+    fn matches(&self, p: &PackageMeta, reason: Reason) -> bool {
+        self.entry
+            .only_for
+            .as_ref()
+            .is_none_or(|r| r.contains(&reason))
+            && self.package.matches(&p.package)
+            && self.version.as_ref().is_none_or(|v| v.matches(&p.version))
+            && self
+                .architecture
+                .as_ref()
+                .is_none_or(|a| a.matches(&p.architecture))
+    }
+}
+
+pub struct ProtectList {
+    matchers: Vec<Matcher>,
+}
+
+/// The expiry date of the entry, which only takes the date into account.
+fn expiry_date(entry: &ProtectEntry) -> Result<Option<NaiveDate>> {
+    let Some(expires) = &entry.expires else {
+        return Ok(None);
+    };
+    let date = expires
+        .date
+        .and_then(|d| NaiveDate::from_ymd_opt(d.year.into(), d.month.into(), d.day.into()))
+        .with_context(|| format!("invalid expiry date in {}", entry))?;
+
+    Ok(Some(date))
+}
+
+impl ProtectList {
+    /// Parses the protect-list, leaving out the entries expired by `today`.
+    /// The default entries follow unless `defaults` is false.
+    fn parse(content: &str, today: NaiveDate, defaults: bool) -> Result<Self> {
+        let file: ProtectFile = toml::from_str(content)?;
+        let defaults = if defaults {
+            toml::from_str(DEFAULT_PROTECT)?
+        } else {
+            ProtectFile {
+                protect: Vec::new(),
+            }
+        };
+        let mut matchers = Vec::new();
+        for entry in file.protect.into_iter().chain(defaults.protect) {
+            if expiry_date(&entry)?.is_some_and(|date| date < today) {
+                info!("Protection expired: {}", entry);
+                continue;
+            }
+            matchers.push(Matcher::new(entry)?);
+        }
+
+        Ok(Self { matchers })
+    }
+
+    /// Loads the protect-list at the path, or only the default entries
+    /// without one.
+    pub fn load(path: Option<&Path>, defaults: bool) -> Result<Self> {
+        let today = chrono::Local::now().date_naive();
+        let Some(path) = path else {
+            return Self::parse("", today, defaults);
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("when reading {}", path.display()))?;
+        Self::parse(&content, today, defaults)
+            .with_context(|| format!("when parsing {}", path.display()))
+    }
+
+    /// Finds the entry protecting the package retired for the reason, if
+    /// any.
+    pub fn find(&self, p: &PackageMeta, reason: Reason) -> Option<&ProtectEntry> {
+        self.matchers
+            .iter()
+            .find(|m| m.matches(p, reason))
+            .map(|m| &m.entry)
+    }
+}
+
+#[test]
+fn test_protect_list() -> Result<()> {
+    use crate::testing::package;
+
+    let today = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+    let list = ProtectList::parse(
+        r#"
+        [[protect]]
+        package = "u-boot-*"
+        reason = "boot loaders"
+
+        [[protect]]
+        package = "glibc"
+        version = "2.36-*"
+        architecture = "loongson3"
+        reason = "old firmware"
+        expires = 2024-06-30
+
+        [[protect]]
+        package = "wget"
+        reason = "expired"
+        expires = 2024-01-01
+        "#,
+        today,
+        false,
+    )?;
+    let found = |p: PackageMeta| list.find(&p, Reason::Superseded).map(|e| e.to_string());
+    assert_eq!(
+        found(package("u-boot-rpi", "2023.01", "arm64")).as_deref(),
+        Some("u-boot-*: boot loaders")
+    );
+    assert_eq!(
+        found(package("glibc", "2.36-1", "loongson3")).as_deref(),
+        Some("glibc 2.36-* [loongson3]: old firmware (until 2024-06-30)")
+    );
+    assert!(found(package("glibc", "2.36-1", "amd64")).is_none());
+    assert!(found(package("glibc", "2.37-0", "loongson3")).is_none());
+    assert!(found(package("wget", "1.0", "amd64")).is_none());
+    assert!(found(package("glibc-dbg", "2.36-1", "amd64")).is_none());
+    // protected from out-of-tree retirement by default
+    let defaults = ProtectList::parse("", today, true)?;
+    let found = |p: PackageMeta, reason| defaults.find(&p, reason).map(|e| e.to_string());
+    assert_eq!(
+        found(package("u-boot-rpi", "2023.01", "arm64"), Reason::OutOfTree).as_deref(),
+        Some("u-boot* (out-of-tree only): Needed to recover boards with broken boot loaders")
+    );
+    for name in ["glibc-dbg", "linux-kernel-6.6.10", "linux+kernel+lts"] {
+        assert!(found(package(name, "1.0", "amd64"), Reason::OutOfTree).is_some());
+    }
+    assert!(found(
+        package("u-boot-rpi", "2023.01", "arm64"),
+        Reason::Superseded
+    )
+    .is_none());
+    assert!(found(
+        package("glibc-dbg", "2.36-1", "amd64"),
+        Reason::OrphanedDebug
+    )
+    .is_none());
+    assert!(found(package("wget", "1.0", "amd64"), Reason::OutOfTree).is_none());
+    // the reason is required
+    assert!(ProtectList::parse("[[protect]]\npackage = \"wget\"\n", today, true).is_err());
+    Ok(())
+}
@@ -0,0 +1,265 @@
+//! Structured plan of a retirement, for reviewing it before the real run.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::db::{PackageMeta, Reason, TopicStatus};
+use crate::kernel::{KernelCompanion, KernelVersion};
+use crate::plan::Plan;
+
+/// What happens to the package in the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Moved into the archive directory
+    Move,
+    /// Deleted, as it is already archived elsewhere
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportEntry {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: String,
+    pub size: i64,
+    pub sha256: String,
+    pub filename: String,
+    pub reason: Reason,
+    /// The retention rule applied
+    pub rule: Option<String>,
+    pub action: Action,
+    /// Where the package is already archived
+    pub location: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Total {
+    pub repo: String,
+    pub architecture: String,
+    pub packages: usize,
+    pub size: i64,
+}
+
+/// A package which is not retired, and why.
+#[derive(Debug, Serialize)]
+pub struct KeptEntry {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub repo: String,
+    pub filename: String,
+    pub why: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub packages: usize,
+    pub size: i64,
+    pub totals: Vec<Total>,
+    pub entries: Vec<ReportEntry>,
+    /// Candidates kept for now
+    pub kept: Vec<KeptEntry>,
+    /// Packages on the protect-list
+    pub protected: Vec<KeptEntry>,
+    /// Topics not fully merged into stable
+    pub unmerged_topics: Vec<TopicStatus>,
+    /// The kernel versions of each variant
+    pub kernels: Vec<KernelVersion>,
+    /// The packages built for each retired kernel
+    pub kernel_companions: Vec<KernelCompanion>,
+}
+
+/// Writes the rows to the CSV file, with a header.
+fn write_csv<T: Serialize>(path: &Path, rows: &[T]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows.iter() {
+        writer.serialize(row)?;
+    }
+    std::fs::write(path, writer.into_inner()?)
+        .with_context(|| format!("when writing {}", path.display()))
+}
+
+impl Report {
+    /// Builds the report of the packages to move and those to delete in the
+    /// plan, with the reason each of them is retired for.
+    pub fn new(plan: &Plan) -> Self {
+        let moved = plan.packages.iter().map(|p| (p, Action::Move, None));
+        let deleted = plan
+            .archived
+            .iter()
+            .map(|(p, location)| (p, Action::Delete, Some(location.clone())));
+        let mut entries = moved
+            .chain(deleted)
+            .map(|(p, action, location)| ReportEntry {
+                package: p.package.clone(),
+                version: p.version.clone(),
+                architecture: p.architecture.clone(),
+                repo: p.repo.clone(),
+                size: p.size,
+                sha256: p.sha256.clone(),
+                filename: p.filename.clone(),
+                reason: plan.reasons[&p.filename],
+                rule: plan.rules.get(&p.filename).cloned(),
+                action,
+                location,
+            })
+            .collect::<Vec<_>>();
+        // sorted, so that the reports of two runs can be diffed
+        entries.sort_unstable_by(|a, b| a.filename.cmp(&b.filename));
+        let mut totals: BTreeMap<(&str, &str), (usize, i64)> = BTreeMap::new();
+        for entry in entries.iter() {
+            let total = totals
+                .entry((&entry.repo, &entry.architecture))
+                .or_default();
+            total.0 += 1;
+            total.1 += entry.size;
+        }
+        let totals = totals
+            .into_iter()
+            .map(|((repo, architecture), (packages, size))| Total {
+                repo: repo.to_owned(),
+                architecture: architecture.to_owned(),
+                packages,
+                size,
+            })
+            .collect();
+
+        let kept = |packages: &[(PackageMeta, String)]| {
+            packages
+                .iter()
+                .map(|(p, why)| KeptEntry {
+                    package: p.package.clone(),
+                    version: p.version.clone(),
+                    architecture: p.architecture.clone(),
+                    repo: p.repo.clone(),
+                    filename: p.filename.clone(),
+                    why: why.clone(),
+                })
+                .collect()
+        };
+
+        Self {
+            packages: entries.len(),
+            size: entries.iter().map(|e| e.size).sum(),
+            totals,
+            entries,
+            kept: kept(&plan.kept),
+            protected: kept(&plan.protected),
+            unmerged_topics: plan.unmerged_topics.clone(),
+            kernels: plan.kernels.clone(),
+            kernel_companions: plan.kernel_companions.clone(),
+        }
+    }
+
+    /// Writes the entries to the CSV file, and the totals, the kept and the
+    /// protected packages, the unmerged topics, the kernel versions and the
+    /// companions of the retired kernels each to a CSV file next to it, as
+    /// `plan.totals.csv` for `plan.csv`.
+    fn write_csv(&self, path: &Path) -> Result<()> {
+        write_csv(path, &self.entries)?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let side = |name: &str| path.with_file_name(format!("{}.{}.csv", stem, name));
+        write_csv(&side("totals"), &self.totals)?;
+        write_csv(&side("kept"), &self.kept)?;
+        write_csv(&side("protected"), &self.protected)?;
+        write_csv(&side("topics"), &self.unmerged_topics)?;
+        write_csv(&side("kernels"), &self.kernels)?;
+        write_csv(&side("kernel-companions"), &self.kernel_companions)
+    }
+
+    /// Writes the report as CSV files if the path ends with `.csv`, or as
+    /// JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => self.write_csv(path),
+            _ => std::fs::write(path, serde_json::to_vec_pretty(self)?)
+                .with_context(|| format!("when writing {}", path.display())),
+        }
+    }
+}
+
+#[test]
+fn test_report() -> Result<()> {
+    let package = |name: &str, arch: &str, size: i64| PackageMeta {
+        sha256: format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(name)),
+        size,
+        repo: "amd64/stable".to_owned(),
+        ..crate::testing::package(name, "1.0-0", arch)
+    };
+    let mut plan = Plan {
+        packages: vec![
+            package("webkit2gtk", "amd64", 100),
+            package("wget", "amd64", 10),
+        ],
+        archived: vec![(package("wayland", "noarch", 1), "disc-3:./x".to_owned())],
+        ..Default::default()
+    };
+    plan.reasons = plan
+        .packages
+        .iter()
+        .chain(plan.archived.iter().map(|(p, _)| p))
+        .map(|p| (p.filename.clone(), Reason::Superseded))
+        .collect();
+    plan.kept.push((
+        package("webkit2gtk", "arm64", 100),
+        "superseded on 2024-01-01".to_owned(),
+    ));
+    let wget = plan.packages[1].filename.clone();
+    plan.rules
+        .insert(wget, "repo amd64/stable: keep 3".to_owned());
+    let report = Report::new(&plan);
+    let root = crate::testing::TempDir::new("report")?;
+    report.write(&root.join("plan.csv"))?;
+    report.write(&root.join("plan.json"))?;
+    let csv = std::fs::read_to_string(root.join("plan.csv"))?;
+    let totals = std::fs::read_to_string(root.join("plan.totals.csv"))?;
+    let kept = std::fs::read_to_string(root.join("plan.kept.csv"))?;
+    let protected = std::fs::read_to_string(root.join("plan.protected.csv"))?;
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(root.join("plan.json"))?)?;
+    drop(root);
+    assert_eq!(report.packages, 3);
+    assert_eq!(report.size, 111);
+    assert_eq!(
+        report.totals,
+        vec![
+            Total {
+                repo: "amd64/stable".to_owned(),
+                architecture: "amd64".to_owned(),
+                packages: 2,
+                size: 110,
+            },
+            Total {
+                repo: "amd64/stable".to_owned(),
+                architecture: "noarch".to_owned(),
+                packages: 1,
+                size: 1,
+            },
+        ]
+    );
+    assert_eq!(report.entries[0].package, "wayland");
+    assert_eq!(report.entries[0].action, Action::Delete);
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next(),
+        Some("package,version,architecture,repo,size,sha256,filename,reason,rule,action,location")
+    );
+    assert!(lines
+        .next()
+        .is_some_and(|l| l.starts_with("wayland,1.0-0,noarch,")
+            && l.ends_with(",superseded,,delete,disc-3:./x")));
+    assert_eq!(csv.lines().count(), 4);
+    assert!(totals.starts_with("repo,architecture,packages,size\namd64/stable,amd64,2,110\n"));
+    assert_eq!(json["entries"][2]["reason"], "superseded");
+    assert_eq!(json["entries"][2]["rule"], "repo amd64/stable: keep 3");
+    assert_eq!(json["totals"][1]["packages"], 1);
+    assert_eq!(kept, "package,version,architecture,repo,filename,why\nwebkit2gtk,1.0-0,arm64,amd64/stable,pool/stable/main/w/webkit2gtk_1.0-0_arm64.deb,superseded on 2024-01-01\n");
+    assert_eq!(protected, "");
+    assert_eq!(json["kept"][0]["why"], "superseded on 2024-01-01");
+    assert_eq!(json["protected"], serde_json::json!([]));
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+
+use crate::catalog::find_label_dbs;
+use crate::cli::RestoreArgs;
+use crate::db::{find_archived_files, mark_restored, ArchivedFile};
+use crate::manifest::sha256_file;
+use crate::retire::load_config;
+
+/// An archived copy of a file, and the archive database recording it.
+#[derive(Debug)]
+struct Candidate {
+    db_path: PathBuf,
+    source: PathBuf,
+    file: ArchivedFile,
+}
+
+#[derive(Debug, Default)]
+struct Candidates {
+    /// Archived copies, grouped by their paths in the pool
+    files: BTreeMap<String, Vec<Candidate>>,
+    /// Pointers to the copies of packages that were already archived elsewhere
+    elsewhere: Vec<String>,
+}
+
+/// Finds the archived copies of the version of the package.
+fn find_candidates(
+    labels: &[String],
+    package: &str,
+    version: &str,
+    arch: Option<&str>,
+) -> Result<Candidates> {
+    let mut candidates = Candidates::default();
+    for db_path in find_label_dbs(labels)? {
+        let archive_dir = db_path.parent().unwrap_or(Path::new("."));
+        let files = find_archived_files(&db_path, package, version, arch)
+            .with_context(|| format!("when reading {}", db_path.display()))?;
+        for file in files {
+            match file.status.as_str() {
+                "already-archived" => {
+                    candidates.elsewhere.extend(file.location);
+                    continue;
+                }
+                "rolled-back" => continue,
+                _ => (),
+            }
+            candidates
+                .files
+                .entry(file.filename.clone())
+                .or_default()
+                .push(Candidate {
+                    db_path: db_path.clone(),
+                    source: archive_dir.join(&file.filename),
+                    file,
+                });
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Copies the file into the pool through a temporary file, which is only
+/// renamed into place once its checksum matches.
+pub fn copy_verified(source: &Path, dest: &Path, sha256: &str) -> Result<()> {
+    let parent = dest.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("when creating directory {}", parent.display()))?;
+    let mut temp = dest.as_os_str().to_owned();
+    temp.push(".restoring");
+    let temp = PathBuf::from(temp);
+    std::fs::copy(source, &temp).with_context(|| format!("when copying {}", source.display()))?;
+    let actual = sha256_file(&temp)?;
+    if actual != sha256 {
+        std::fs::remove_file(&temp)?;
+        bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            source.display(),
+            sha256,
+            actual
+        );
+    }
+    std::fs::File::open(&temp)?.sync_all()?;
+    std::fs::rename(&temp, dest).with_context(|| format!("when renaming {}", temp.display()))?;
+    std::fs::File::open(parent)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Restores a file from the first of its archived copies that is intact.
+fn restore_file(
+    filename: &str,
+    candidates: &[Candidate],
+    pool: &Path,
+    dry_run: bool,
+) -> Result<()> {
+    let dest = pool.join(filename);
+    // every copy of the same file has the same checksum
+    let sha256 = &candidates[0].file.sha256;
+    if dest.exists() {
+        let actual = sha256_file(&dest)?;
+        if actual != *sha256 {
+            bail!(
+                "{} exists in the pool with a different checksum, refusing to overwrite it",
+                dest.display()
+            );
+        }
+        info!("Already in the pool: {}", filename);
+    } else {
+        let mut restored = false;
+        for candidate in candidates.iter() {
+            if !candidate.source.is_file() {
+                warn!("Missing archived copy: {}", candidate.source.display());
+                continue;
+            }
+            if dry_run {
+                info!(
+                    "[DRY-RUN] Would restore {} from {}",
+                    filename,
+                    candidate.source.display()
+                );
+                restored = true;
+                break;
+            }
+            match copy_verified(&candidate.source, &dest, sha256) {
+                Ok(()) => {
+                    info!("Restored {} from {}", filename, candidate.source.display());
+                    restored = true;
+                    break;
+                }
+                Err(e) => error!("{:?}", e),
+            }
+        }
+        if !restored {
+            bail!("No intact archived copy of {} is available", filename);
+        }
+    }
+    if !dry_run {
+        for candidate in candidates.iter() {
+            mark_restored(&candidate.db_path, filename)
+                .with_context(|| format!("when updating {}", candidate.db_path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn restore_action(args: &RestoreArgs) -> Result<()> {
+    let config = load_config(&args.config).await?;
+    let pool = PathBuf::from(&config.config.path);
+    let labels = args.labels.clone();
+    let package = args.package.clone();
+    let version = args.version.clone();
+    let arch = args.arch.clone();
+    let dry_run = args.dry_run;
+    tokio::task::spawn_blocking(move || {
+        let candidates = find_candidates(&labels, &package, &version, arch.as_deref())?;
+        if candidates.files.is_empty() {
+            if !candidates.elsewhere.is_empty() {
+                error!("{} {} is only archived at:", package, version);
+                for location in candidates.elsewhere {
+                    error!(" - {}", location);
+                }
+            }
+            bail!("No archived copy of {} {} found", package, version);
+        }
+        info!(
+            "Restoring {} files of {} {} into {} ...",
+            candidates.files.len(),
+            package,
+            version,
+            pool.display()
+        );
+        let mut failed = 0;
+        for (filename, candidates) in candidates.files.iter() {
+            if let Err(e) = restore_file(filename, candidates, &pool, dry_run) {
+                failed += 1;
+                error!("{:?}", e);
+            }
+        }
+        if failed > 0 {
+            bail!("Failed to restore {} files", failed);
+        }
+
+        Ok(())
+    })
+    .await?
+}
+
+#[test]
+fn test_restore_file() -> Result<()> {
+    let root = crate::testing::TempDir::new("restore")?;
+    let pool = root.join("debs");
+    let batch = root.join("archive-20240101");
+    let filename = "pool/stable/main/w/webkit2gtk_2.20.0-0_amd64.deb";
+    std::fs::create_dir_all(batch.join("pool/stable/main/w"))?;
+    std::fs::write(batch.join(filename), "webkit2gtk")?;
+    let sha256 = sha256_file(&batch.join(filename))?;
+    let db_path = batch.join("labels-20240101.db");
+    let stable = crate::db::PackageMeta {
+        sha256: sha256.clone(),
+        size: 10,
+        ..crate::testing::package("webkit2gtk", "2.20.0-0", "amd64")
+    };
+    // the same file, archived from a topic
+    let topic = crate::db::PackageMeta {
+        filename: "pool/webkit/main/w/webkit2gtk_2.20.0-0_amd64.deb".to_owned(),
+        repo: "amd64/webkit".to_owned(),
+        ..stable.clone()
+    };
+    crate::db::save_new_packages(&db_path, &[stable, topic])?;
+    let labels = [root.to_string_lossy().into_owned()];
+    let candidates = find_candidates(&labels, "webkit2gtk", "2.20.0-0", None)?.files;
+    let none = find_candidates(&labels, "webkit2gtk", "2.20.0-0", Some("arm64"))?.files;
+    let files = &candidates[filename];
+    restore_file(filename, files, &pool, true)?;
+    let dry_run_restored = pool.join(filename).exists();
+    restore_file(filename, files, &pool, false)?;
+    let restored = sha256_file(&pool.join(filename))?;
+    // restoring again is fine, but never over a different file
+    restore_file(filename, files, &pool, false)?;
+    std::fs::write(pool.join(filename), "corrupted")?;
+    let overwrite = restore_file(filename, files, &pool, false);
+    let statuses = find_archived_files(&db_path, "webkit2gtk", "2.20.0-0", None)?
+        .into_iter()
+        .map(|f| (f.filename, f.status))
+        .collect::<BTreeMap<_, _>>();
+    drop(root);
+    assert_eq!(candidates.len(), 2);
+    assert!(none.is_empty());
+    assert!(!dry_run_restored);
+    assert_eq!(restored, sha256);
+    assert!(overwrite.is_err());
+    assert_eq!(statuses[filename], "restored");
+    assert_eq!(
+        statuses["pool/webkit/main/w/webkit2gtk_2.20.0-0_amd64.deb"],
+        "archived"
+    );
+    Ok(())
+}
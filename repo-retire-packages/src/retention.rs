@@ -0,0 +1,236 @@
+//! How many versions of a package to keep in each repository.
+//!
+//! ```toml
+//! [retention]
+//! keep = 1
+//! min_age_after_superseded = "30d"
+//!
+//! [retention.repos]
+//! "amd64/stable" = 3
+//!
+//! [[retention.packages]]
+//! pattern = "gcc*"
+//! keep = 2
+//! ```
+//!
+//! The first package pattern matching a package wins, then the setting of
+//! its repository, then the default. Versions beyond those are still kept
+//! until the version replacing them has been in the pool for
+//! `min_age_after_superseded`, so users can downgrade after a regression.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+fn default_keep() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PackageRetention {
+    /// Shell-style glob of the package names
+    pub pattern: String,
+    pub keep: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionConfig {
+    /// Number of versions to keep by default
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// Number of versions to keep in each repository
+    #[serde(default)]
+    pub repos: BTreeMap<String, usize>,
+    /// Number of versions to keep of the matching packages
+    #[serde(default)]
+    pub packages: Vec<PackageRetention>,
+    /// How long to keep a version after it is superseded, e.g. `30d`
+    pub min_age_after_superseded: Option<String>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            keep: default_keep(),
+            repos: BTreeMap::new(),
+            packages: Vec::new(),
+            min_age_after_superseded: None,
+        }
+    }
+}
+
+/// The rule deciding how many versions of a package to keep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetentionRule<'a> {
+    Default(usize),
+    Repo(&'a str, usize),
+    Package(&'a str, usize),
+}
+
+impl RetentionRule<'_> {
+    pub fn keep(&self) -> usize {
+        match self {
+            Self::Default(keep) | Self::Repo(_, keep) | Self::Package(_, keep) => *keep,
+        }
+    }
+}
+
+impl fmt::Display for RetentionRule<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default(keep) => write!(f, "default: keep {}", keep),
+            Self::Repo(repo, keep) => write!(f, "repo {}: keep {}", repo, keep),
+            Self::Package(pattern, keep) => write!(f, "package {}: keep {}", pattern, keep),
+        }
+    }
+}
+
+/// Parses an age like `90s`, `12h`, `30d` or `2w` into seconds.
+fn parse_age(age: &str) -> Result<i64> {
+    let unit = match age.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => bail!(
+            "invalid age {}, expected a number followed by s, m, h, d or w",
+            age
+        ),
+    };
+    let value: i64 = age[..age.len() - 1]
+        .parse()
+        .with_context(|| format!("invalid age {}", age))?;
+
+    Ok(value * unit)
+}
+
+/// The retention settings, with the package patterns compiled.
+pub struct Retention {
+    config: RetentionConfig,
+    patterns: Vec<glob::Pattern>,
+    /// Seconds to keep a version after it is superseded
+    min_age: Option<i64>,
+}
+
+impl Retention {
+    pub fn new(config: RetentionConfig) -> Result<Self> {
+        let patterns = config
+            .packages
+            .iter()
+            .map(|p| {
+                glob::Pattern::new(&p.pattern)
+                    .with_context(|| format!("invalid package pattern {}", p.pattern))
+            })
+            .collect::<Result<_>>()?;
+        let min_age = config
+            .min_age_after_superseded
+            .as_deref()
+            .map(parse_age)
+            .transpose()?;
+
+        Ok(Self {
+            config,
+            patterns,
+            min_age,
+        })
+    }
+
+    /// Tells why a version superseded at `superseded_at` is kept at `now`,
+    /// both in seconds since the epoch, if it is too recent to retire.
+    pub fn keep_recent(&self, superseded_at: i64, now: i64) -> Option<String> {
+        let until = superseded_at + self.min_age?;
+        if until <= now {
+            return None;
+        }
+        let date = |t| {
+            chrono::DateTime::from_timestamp(t, 0)
+                .map(|t| t.format("%Y-%m-%d").to_string())
+                .unwrap_or_default()
+        };
+
+        Some(format!(
+            "superseded on {}, kept until {} (min_age_after_superseded: {})",
+            date(superseded_at),
+            date(until),
+            self.config
+                .min_age_after_superseded
+                .as_deref()
+                .unwrap_or_default()
+        ))
+    }
+
+    pub fn rule(&self, package: &str, repo: &str) -> RetentionRule<'_> {
+        let matched = self
+            .patterns
+            .iter()
+            .zip(self.config.packages.iter())
+            .find(|(pattern, _)| pattern.matches(package));
+        if let Some((_, rule)) = matched {
+            return RetentionRule::Package(&rule.pattern, rule.keep);
+        }
+        if let Some((repo, keep)) = self.config.repos.get_key_value(repo) {
+            return RetentionRule::Repo(repo, *keep);
+        }
+
+        RetentionRule::Default(self.config.keep)
+    }
+}
+
+#[test]
+fn test_retention_rule() -> Result<()> {
+    #[derive(Deserialize)]
+    struct Config {
+        #[serde(default)]
+        retention: RetentionConfig,
+    }
+    let config: Config = toml::from_str(
+        r#"
+        [retention.repos]
+        "amd64/stable" = 3
+        "loongson3/stable" = 1
+
+        [[retention.packages]]
+        pattern = "gcc*"
+        keep = 2
+
+        [[retention.packages]]
+        pattern = "glibc"
+        keep = 2
+        "#,
+    )?;
+    let retention = Retention::new(config.retention)?;
+    assert_eq!(
+        retention.rule("gcc-runtime", "amd64/stable"),
+        RetentionRule::Package("gcc*", 2)
+    );
+    assert_eq!(retention.rule("glibc", "loongson3/stable").keep(), 2);
+    assert_eq!(
+        retention.rule("wget", "amd64/stable"),
+        RetentionRule::Repo("amd64/stable", 3)
+    );
+    assert_eq!(
+        retention.rule("wget", "arm64/stable").to_string(),
+        "default: keep 1"
+    );
+    let config: Config = toml::from_str("retention.min_age_after_superseded = \"30d\"")?;
+    let retention = Retention::new(config.retention)?;
+    let day = 24 * 60 * 60;
+    assert_eq!(
+        retention.keep_recent(0, 10 * day).as_deref(),
+        Some("superseded on 1970-01-01, kept until 1970-01-31 (min_age_after_superseded: 30d)")
+    );
+    assert!(retention.keep_recent(0, 30 * day).is_none());
+    assert_eq!(parse_age("2w")?, 14 * day);
+    assert!(parse_age("30").is_err());
+    let empty: Config = toml::from_str("")?;
+    assert_eq!(
+        Retention::new(empty.retention)?
+            .rule("wget", "arm64/stable")
+            .keep(),
+        1
+    );
+    Ok(())
+}
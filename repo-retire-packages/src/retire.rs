@@ -1,30 +1,51 @@
 use anyhow::{bail, Context, Result};
 use bytesize::ByteSize;
-use log::{error, info};
+use log::{error, info, warn};
 use serde::Deserialize;
 use sqlx::PgPool;
-use std::sync::atomic::AtomicUsize;
-use std::{path::Path, sync::atomic::Ordering};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 
 use crate::abbs::update_abbs_database;
+use crate::catalog::{disc_of, ArchiveIndex};
+use crate::cli::RetireArgs;
 use crate::db::{
-    determine_retired_kernel_packages, determine_retired_packages, save_new_packages, PackageMeta,
+    determine_orphaned_dbg_packages, determine_retired_kernel_packages, determine_retired_packages,
+    find_bounded_dependencies, find_packages_by_filename, find_packages_in_repos,
+    find_pool_versions, find_topics, is_recorded, mark_rolled_back, save_archived_packages,
+    save_new_packages, Dependency, PackageMeta, PoolVersion, Reason, RetiredPackages,
 };
+use crate::depends::keep_depended_on;
+use crate::journal::{Journal, JournalEntry, JOURNAL_NAME};
+use crate::kernel::{KernelCompanion, KernelConfig, KernelPolicy};
+use crate::manifest::sha256_file;
+use crate::plan::Plan;
+use crate::protect::ProtectList;
+use crate::report::Report;
+use crate::retention::{Retention, RetentionConfig};
+use crate::rollback::{put_back, PutBack};
 
 #[derive(Debug, Deserialize)]
-struct Config {
-    config: GeneralConfig,
+pub struct Config {
+    pub config: GeneralConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub kernel: KernelConfig,
 }
 
 #[derive(Debug, Deserialize)]
-struct GeneralConfig {
+pub struct GeneralConfig {
     pub db_pgconn: String,
     pub path: String,
     pub abbs_sync: bool,
 }
 
-async fn load_config<P: AsRef<Path>>(path: P) -> Result<Config> {
+pub async fn load_config<P: AsRef<Path>>(path: P) -> Result<Config> {
     let mut f = tokio::fs::File::open(path).await?;
     let mut buffer = String::new();
     buffer.reserve(1024);
@@ -33,16 +54,89 @@ async fn load_config<P: AsRef<Path>>(path: P) -> Result<Config> {
     Ok(toml::from_str(&buffer)?)
 }
 
-pub async fn retire_action<P: AsRef<Path>>(
-    config_file: P,
-    dry_run: bool,
-    output: P,
-    oot: bool,
-    kernel: bool,
-    db_path: P,
-    abbs_path: P,
-) -> Result<()> {
-    let config = load_config(config_file).await?;
+/// Keeps the packages built for the protected kernels, along with them, and
+/// leaves out the companions which are not retired.
+fn keep_protected_companions(
+    packages: &mut Vec<(PackageMeta, Reason)>,
+    kernel_companions: &mut Vec<KernelCompanion>,
+    protected: &[(PackageMeta, String)],
+) -> Vec<(PackageMeta, String)> {
+    let protected_kernels = protected
+        .iter()
+        .map(|(p, _)| (p.package.as_str(), p.repo.as_str()))
+        .collect::<HashSet<_>>();
+    let mut kept_for = BTreeMap::new();
+    kernel_companions.retain(|c| {
+        let protected = protected_kernels.contains(&(c.kernel.as_str(), c.repo.as_str()));
+        if protected {
+            kept_for.insert(c.filename.clone(), c.kernel.clone());
+        }
+        !protected
+    });
+    let mut kept: Vec<(PackageMeta, String)> = Vec::new();
+    packages.retain(|(p, _)| {
+        let Some(kernel) = kept_for.get(&p.filename) else {
+            return true;
+        };
+        if !kept.iter().any(|(q, _)| q.filename == p.filename) {
+            kept.push((
+                p.clone(),
+                format!("built for {}, which is protected", kernel),
+            ));
+        }
+        false
+    });
+    let retiring = packages
+        .iter()
+        .map(|(p, _)| p.filename.as_str())
+        .collect::<HashSet<_>>();
+    kernel_companions.retain(|c| retiring.contains(c.filename.as_str()));
+
+    kept
+}
+
+/// Keeps the candidates that the packages staying in the pool depend on,
+/// whatever they are retired for, and leaves out the companions kept.
+fn keep_needed(
+    packages: &mut Vec<(PackageMeta, Reason)>,
+    kernel_companions: &mut Vec<KernelCompanion>,
+    versions: &[PoolVersion],
+    dependencies: &[Dependency],
+) -> Vec<(PackageMeta, String)> {
+    let needed = keep_depended_on(std::mem::take(packages), versions, dependencies);
+    *packages = needed.packages;
+    let retiring = packages
+        .iter()
+        .map(|(p, _)| p.filename.as_str())
+        .collect::<HashSet<_>>();
+    kernel_companions.retain(|c| retiring.contains(c.filename.as_str()));
+
+    needed.kept
+}
+
+pub async fn retire_action(args: &RetireArgs) -> Result<()> {
+    if let Some(journal) = &args.resume {
+        return resume_retirement(Path::new(journal)).await;
+    }
+    if let Some(journal) = &args.rollback {
+        return rollback_retirement(Path::new(journal)).await;
+    }
+    let (Some(config_file), Some(output), Some(db_path), Some(abbs_path)) =
+        (&args.config, &args.output, &args.database, &args.abbs_dir)
+    else {
+        bail!("The config file, output directory, database and ABBS tree are all required");
+    };
+    let dry_run = args.dry_run;
+    let output = Path::new(output);
+    let oot = args.out_of_tree;
+    let kernel = args.with_kernel;
+    let topics = args.topics;
+    let orphaned_dbg = args.orphaned_dbg;
+    let db_path = Path::new(db_path);
+    let abbs_path = Path::new(abbs_path);
+    let mut config = load_config(config_file).await?;
+    let retention = Retention::new(std::mem::take(&mut config.retention))?;
+    let kernel_policy = KernelPolicy::new(std::mem::take(&mut config.kernel))?;
     info!("Connecting to database ...");
     let pool = PgPool::connect(&config.config.db_pgconn).await?;
     if oot {
@@ -55,12 +149,170 @@ pub async fn retire_action<P: AsRef<Path>>(
         error!("Invalid configuration: abbs_sync should be enabled in order to correctly retire packages!");
         bail!("Refusing to continue to avoid damaging package pool")
     }
-    let mut packages = determine_retired_packages(&pool, oot).await?;
+    let RetiredPackages {
+        mut packages,
+        mut kept,
+    } = determine_retired_packages(&pool, oot, &retention).await?;
 
+    let mut kernels = Vec::new();
+    let mut kernel_companions = Vec::new();
     if kernel {
-        let outdated_kernel_packages = determine_retired_kernel_packages(&pool).await?;
-        packages.extend(outdated_kernel_packages);
+        let outdated_kernels = determine_retired_kernel_packages(&pool, &kernel_policy).await?;
+        packages.extend(
+            outdated_kernels
+                .packages
+                .into_iter()
+                .map(|p| (p, Reason::OutdatedKernel)),
+        );
+        kernels = outdated_kernels.versions;
+        for (p, companion) in outdated_kernels.companions {
+            packages.push((p, Reason::KernelCompanion));
+            kernel_companions.push(companion);
+        }
+    }
+    if orphaned_dbg {
+        info!("Looking for -dbg packages left without their base packages ...");
+        let orphaned_dbg_packages = determine_orphaned_dbg_packages(&pool).await?;
+        packages.extend(
+            orphaned_dbg_packages
+                .into_iter()
+                .map(|p| (p, Reason::OrphanedDebug)),
+        );
     }
+    let mut unmerged_topics = Vec::new();
+    if topics {
+        info!("Looking for topics merged into stable ...");
+        let (merged, unmerged): (Vec<_>, Vec<_>) = find_topics(&pool)
+            .await?
+            .into_iter()
+            .partition(|t| t.unmerged == 0);
+        for topic in merged.iter() {
+            info!(
+                "{} is merged into stable, retiring its {} packages",
+                topic.repo, topic.packages
+            );
+        }
+        let repos = merged.into_iter().map(|t| t.repo).collect::<Vec<_>>();
+        let topic_packages = find_packages_in_repos(&pool, &repos).await?;
+        packages.extend(topic_packages.into_iter().map(|p| (p, Reason::MergedTopic)));
+        unmerged_topics = unmerged;
+    }
+    let protect = ProtectList::load(
+        args.protect.as_deref().map(Path::new),
+        !args.no_default_protect,
+    )?;
+    let mut protected: Vec<(PackageMeta, String)> = Vec::new();
+    packages.retain(|(p, reason)| {
+        let Some(entry) = protect.find(p, *reason) else {
+            return true;
+        };
+        if !protected.iter().any(|(q, _)| q.filename == p.filename) {
+            protected.push((p.clone(), entry.to_string()));
+        }
+        false
+    });
+    // still retired for a reason it is not protected from
+    protected.retain(|(p, _)| !packages.iter().any(|(q, _)| q.filename == p.filename));
+    kept.extend(keep_protected_companions(
+        &mut packages,
+        &mut kernel_companions,
+        &protected,
+    ));
+    info!("Looking for candidates still depended on ...");
+    let versions = find_pool_versions(&pool).await?;
+    let dependencies = find_bounded_dependencies(&pool).await?;
+    kept.extend(keep_needed(
+        &mut packages,
+        &mut kernel_companions,
+        &versions,
+        &dependencies,
+    ));
+    let plan = match &args.plan {
+        Some(plan_path) if !dry_run => {
+            let (plan, sha256) = Plan::load(Path::new(plan_path))?;
+            if let Some(expected) = &args.expect_sha256 {
+                if *expected != sha256 {
+                    bail!(
+                        "The plan {} has sha256 {}, not the reviewed {}",
+                        plan_path,
+                        sha256,
+                        expected
+                    );
+                }
+            }
+            info!("Checking the plan {} (sha256 {}) ...", plan_path, sha256);
+            if (
+                plan.out_of_tree,
+                plan.with_kernel,
+                plan.topics,
+                plan.orphaned_dbg,
+            ) != (oot, kernel, topics, orphaned_dbg)
+            {
+                bail!("The plan was made with different --out-of-tree, --with-kernel, --topics or --orphaned-dbg options");
+            }
+            let filenames = plan
+                .packages
+                .iter()
+                .chain(plan.archived.iter().map(|(p, _)| p))
+                .map(|p| p.filename.clone())
+                .collect::<Vec<_>>();
+            let known = find_packages_by_filename(&pool, &filenames).await?;
+            Plan {
+                unmerged_topics,
+                kernels,
+                kernel_companions,
+                ..plan.check(&packages, &known)?
+            }
+        }
+        _ => {
+            let mut reasons = BTreeMap::new();
+            let mut rules = BTreeMap::new();
+            // a package may be retired for more than one reason
+            let packages = packages
+                .into_iter()
+                .filter_map(|(p, reason)| match reasons.entry(p.filename.clone()) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(reason);
+                        if reason == Reason::Superseded {
+                            let rule = retention.rule(&p.package, &p.repo);
+                            rules.insert(p.filename.clone(), rule.to_string());
+                        }
+                        Some(p)
+                    }
+                    Entry::Occupied(_) => None,
+                })
+                .collect();
+            info!("Checking for packages already in cold storage ...");
+            let (packages, archived) = find_archived_packages(
+                packages,
+                &args.labels,
+                args.catalog.as_deref(),
+                db_path,
+                Path::new(&config.config.path),
+            )
+            .await?;
+            let kept = kept
+                .into_iter()
+                .filter(|(p, _)| !reasons.contains_key(&p.filename))
+                .collect();
+            Plan {
+                out_of_tree: oot,
+                with_kernel: kernel,
+                topics,
+                orphaned_dbg,
+                packages,
+                archived,
+                reasons,
+                rules,
+                kept,
+                protected,
+                unmerged_topics,
+                kernels,
+                kernel_companions,
+            }
+        }
+    };
+    let (packages, archived) = (&plan.packages, &plan.archived);
 
     let total_size = packages.iter().fold(0, |t, x| t + x.size);
     let total_count = packages.len();
@@ -71,65 +323,475 @@ pub async fn retire_action<P: AsRef<Path>>(
         ByteSize::b(total_size as u64).to_string_as(true)
     );
 
+    if let Some(report) = &args.report {
+        Report::new(&plan).write(Path::new(report))?;
+        info!("Wrote the plan to {}", report);
+    }
+
     if dry_run {
         info!(
             "The following packages would be moved to `{}`:",
-            output.as_ref().display()
+            output.display()
         );
         for p in packages.iter() {
-            info!("{}: {}", p.package, p.filename);
+            info!(
+                "{}: {} [{}]",
+                p.package,
+                p.filename,
+                plan.describe(&p.filename)
+            );
+        }
+        if !archived.is_empty() {
+            info!(
+                "The following packages are already archived and would be deleted from the pool:"
+            );
+            for (p, location) in archived.iter() {
+                info!(
+                    "{}: {} [{}] (archived at {})",
+                    p.package,
+                    p.filename,
+                    plan.describe(&p.filename),
+                    location
+                );
+            }
+        }
+        if !plan.protected.is_empty() {
+            // the default entries keep thousands of packages, see the report
+            // for each of them
+            let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+            for (_, why) in plan.protected.iter() {
+                *counts.entry(why).or_default() += 1;
+            }
+            info!("The following protect-list entries would keep packages:");
+            for (why, count) in counts {
+                info!("{} ({} packages)", why, count);
+            }
+        }
+        if !plan.unmerged_topics.is_empty() {
+            info!("The following topics are not fully merged into stable, decide by hand:");
+            for topic in plan.unmerged_topics.iter() {
+                info!(
+                    "{}: {} of {} packages not in stable",
+                    topic.repo, topic.unmerged, topic.packages
+                );
+            }
+        }
+        if !plan.kernels.is_empty() {
+            info!("Kernel versions of each variant:");
+            for k in plan.kernels.iter() {
+                info!(
+                    "{} in {}: {} {} ({})",
+                    k.metapackage,
+                    k.repo,
+                    k.version,
+                    if k.retire { "retired" } else { "kept" },
+                    k.why
+                );
+            }
+        }
+        if !plan.kernel_companions.is_empty() {
+            info!("The following packages were built for the retired kernels:");
+            for c in plan.kernel_companions.iter() {
+                info!("{} ({}): {} {}", c.kernel, c.repo, c.package, c.version);
+            }
+        }
+        if !plan.kept.is_empty() {
+            info!("The following packages are kept for now, though they could be retired:");
+            for (p, why) in plan.kept.iter() {
+                info!("{}: {} ({})", p.package, p.filename, why);
+            }
         }
         info!(
             "[DRY-RUN] {} packages would be retired, {} total",
             total_count,
             ByteSize::b(total_size as u64)
         );
+        if plan.orphaned_dbg {
+            // counted apart, as they are found by name alone
+            let orphaned = plan
+                .reasons
+                .values()
+                .filter(|r| **r == Reason::OrphanedDebug)
+                .count();
+            info!(
+                "[DRY-RUN] {} orphaned -dbg packages would be retired or deleted",
+                orphaned
+            );
+        }
+        match &args.plan {
+            Some(plan_path) => {
+                let sha256 = plan.save(Path::new(plan_path))?;
+                info!(
+                    "[DRY-RUN] Saved the plan to {} (sha256 {}), retire with --plan {} --expect-sha256 {}",
+                    plan_path, sha256, plan_path, sha256
+                );
+            }
+            None => info!("[DRY-RUN] Plan sha256: {}", plan.checksum()?),
+        }
         return Ok(());
     }
 
+    tokio::fs::create_dir_all(output).await?;
+    let journal = Journal::create(&output.join(JOURNAL_NAME))
+        .await
+        .context("an earlier retirement into this directory exists, use --resume or --rollback")?;
+    info!("Writing the journal to {} ...", journal.path().display());
+    let pool_path = std::path::absolute(&config.config.path)?;
+    let output = std::path::absolute(output)?;
+    let db_path = std::path::absolute(db_path)?;
+    journal
+        .append(&JournalEntry::Begin {
+            pool: pool_path.clone(),
+            output: output.clone(),
+            database: db_path.clone(),
+        })
+        .await?;
+    for p in packages.iter() {
+        journal
+            .append(&JournalEntry::Move { package: p.clone() })
+            .await?;
+    }
+    for (p, location) in archived.iter() {
+        journal
+            .append(&JournalEntry::Delete {
+                package: p.clone(),
+                location: location.clone(),
+            })
+            .await?;
+    }
+    generate_manifest(packages, archived, &db_path).await?;
+    journal.append(&JournalEntry::Manifest).await?;
+    move_packages(&journal, &pool_path, &output, packages, archived).await
+}
+
+/// The plan and progress of a retirement, read from its journal.
+#[derive(Debug, Default)]
+struct JournalState {
+    pool: PathBuf,
+    output: PathBuf,
+    database: PathBuf,
+    moves: Vec<PackageMeta>,
+    deletes: Vec<(PackageMeta, String)>,
+    manifest: bool,
+    done: HashSet<String>,
+    rolled_back: HashSet<String>,
+}
+
+impl JournalState {
+    fn from_entries(entries: Vec<JournalEntry>) -> Self {
+        let mut state = Self::default();
+        for entry in entries {
+            match entry {
+                JournalEntry::Begin {
+                    pool,
+                    output,
+                    database,
+                } => {
+                    state.pool = pool;
+                    state.output = output;
+                    state.database = database;
+                }
+                JournalEntry::Move { package } => state.moves.push(package),
+                JournalEntry::Delete { package, location } => {
+                    state.deletes.push((package, location))
+                }
+                JournalEntry::Manifest => state.manifest = true,
+                JournalEntry::Done { filename } => {
+                    state.done.insert(filename);
+                }
+                JournalEntry::RolledBack { filename } => {
+                    state.rolled_back.insert(filename);
+                }
+            }
+        }
+
+        state
+    }
+}
+
+/// Finishes an interrupted retirement.
+async fn resume_retirement(journal_path: &Path) -> Result<()> {
+    let (journal, entries) = Journal::open(journal_path).await?;
+    let state = JournalState::from_entries(entries);
+    if !state.rolled_back.is_empty() {
+        bail!("This retirement has been rolled back, refusing to resume it");
+    }
+    if !state.manifest {
+        // each kind of packages is recorded in a single transaction
+        let recorded = |p: Option<&PackageMeta>| match p {
+            Some(p) => is_recorded(&state.database, &p.filename),
+            None => Ok(true),
+        };
+        let moves = if recorded(state.moves.first())? {
+            &[][..]
+        } else {
+            &state.moves[..]
+        };
+        let deletes = if recorded(state.deletes.first().map(|(p, _)| p))? {
+            &[][..]
+        } else {
+            &state.deletes[..]
+        };
+        generate_manifest(moves, deletes, &state.database).await?;
+        journal.append(&JournalEntry::Manifest).await?;
+    }
+    let moves = state
+        .moves
+        .iter()
+        .filter(|p| !state.done.contains(&p.filename))
+        .cloned()
+        .collect::<Vec<_>>();
+    let deletes = state
+        .deletes
+        .iter()
+        .filter(|(p, _)| !state.done.contains(&p.filename))
+        .cloned()
+        .collect::<Vec<_>>();
+    info!(
+        "Resuming the retirement: {} of {} packages left to move, {} of {} to delete",
+        moves.len(),
+        state.moves.len(),
+        deletes.len(),
+        state.deletes.len()
+    );
+    move_packages(&journal, &state.pool, &state.output, &moves, &deletes).await
+}
+
+/// Puts every package moved or deleted by the retirement back in the pool.
+async fn rollback_retirement(journal_path: &Path) -> Result<()> {
+    let (journal, entries) = Journal::open(journal_path).await?;
+    let state = JournalState::from_entries(entries);
+    let count = AtomicUsize::new(1);
+    let total_count = state.moves.len() + state.deletes.len() - state.rolled_back.len();
+    info!("Rolling back {} packages ...", total_count);
+    let mut failed = 0;
+    let mut on_disc = 0;
+    let planned = state
+        .moves
+        .iter()
+        .map(|p| (p, None))
+        .chain(state.deletes.iter().map(|(p, l)| (p, Some(l.as_str()))));
+    for (p, location) in planned {
+        if state.rolled_back.contains(&p.filename) {
+            continue;
+        }
+        let r = put_back(
+            &count,
+            total_count,
+            &p.filename,
+            &p.sha256,
+            location,
+            &state.pool,
+            &state.output,
+        )
+        .await;
+        match r {
+            Ok(PutBack::Done) => (),
+            Ok(PutBack::Missing) => {
+                failed += 1;
+                error!("No copy of {} is left", p.filename);
+                continue;
+            }
+            Ok(PutBack::OnDisc(disc)) => {
+                on_disc += 1;
+                error!(
+                    "{} needs disc {}, copy it back from {}",
+                    p.filename,
+                    disc,
+                    location.unwrap_or_default()
+                );
+                continue;
+            }
+            Err(e) => {
+                failed += 1;
+                error!("Error occurred while moving files: {:?}", e);
+                continue;
+            }
+        }
+        mark_rolled_back(&state.database, &p.filename)?;
+        journal
+            .append(&JournalEntry::RolledBack {
+                filename: p.filename.clone(),
+            })
+            .await?;
+    }
+    if failed > 0 {
+        bail!(
+            "Failed to roll back {} packages, fix the errors and run again with --rollback {}",
+            failed,
+            journal.path().display()
+        );
+    }
+    if on_disc > 0 {
+        bail!(
+            "{} packages are only archived on discs, copy them into the pool and run again with --rollback {}",
+            on_disc,
+            journal.path().display()
+        );
+    }
+    info!("Rolled back {} packages", total_count);
+
+    Ok(())
+}
+
+/// Stops the retirement between chunks on the first Ctrl-C, so that no
+/// file is left halfway. The second one exits right away.
+fn watch_interrupt() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Interrupted, stopping after the files being moved ...");
+            flag.store(true, Ordering::SeqCst);
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    interrupted
+}
+
+/// Moves the packages into the archive, and deletes the ones already
+/// archived elsewhere from the pool, recording the progress in the journal.
+async fn move_packages(
+    journal: &Journal,
+    pool_path: &Path,
+    output_path: &Path,
+    packages: &[PackageMeta],
+    archived: &[(PackageMeta, String)],
+) -> Result<()> {
+    let interrupted = watch_interrupt();
     info!("Moving retired packages ...");
     let count = AtomicUsize::new(1);
-    let output_path = output.as_ref();
-    tokio::fs::create_dir_all(output_path).await?;
-    generate_manifest(&packages, db_path.as_ref()).await?;
+    let total_count = packages.len();
     // move files
     for package_chunk in packages.chunks(40) {
-        let errored =
-            chunked_copy_files(&config, package_chunk, &count, total_count, output_path).await;
+        if interrupted.load(Ordering::SeqCst) {
+            bail!(
+                "Interrupted, run again with --resume {} to finish the retirement",
+                journal.path().display()
+            );
+        }
+        let errored = chunked_copy_files(
+            journal,
+            package_chunk,
+            &count,
+            total_count,
+            pool_path,
+            output_path,
+        )
+        .await;
         if errored {
-            bail!("Errors detected, bailing out ...")
+            bail!(
+                "Errors detected, bailing out ... Run again with --resume {} once they are fixed",
+                journal.path().display()
+            )
+        }
+    }
+    if !archived.is_empty() {
+        info!(
+            "Deleting {} packages already in cold storage ...",
+            archived.len()
+        );
+    }
+    for (p, location) in archived.iter() {
+        let path = pool_path.join(&p.filename);
+        if tokio::fs::metadata(&path).await.is_err() {
+            warn!("Skipping, already deleted: {}", p.filename);
+        } else {
+            let (checked, sha256, copy) = (path.clone(), p.sha256.clone(), location.clone());
+            tokio::task::spawn_blocking(move || check_before_delete(&checked, &sha256, &copy))
+                .await??;
+            tokio::fs::remove_file(&path)
+                .await
+                .with_context(|| format!("when deleting {}", path.display()))?;
+            info!("Deleted {}, archived at {}", p.filename, location);
         }
+        journal
+            .append(&JournalEntry::Done {
+                filename: p.filename.clone(),
+            })
+            .await?;
     }
 
     Ok(())
 }
 
-async fn generate_manifest(packages: &Vec<PackageMeta>, db_path: &Path) -> Result<()> {
+/// Splits the packages into those to archive, and those already archived
+/// in an earlier batch or on a disc, along with where their copies live.
+async fn find_archived_packages(
+    packages: Vec<PackageMeta>,
+    labels: &[String],
+    catalog: Option<&str>,
+    db_path: &Path,
+    pool_path: &Path,
+) -> Result<(Vec<PackageMeta>, Vec<(PackageMeta, String)>)> {
+    let labels = labels.to_owned();
+    let catalog = catalog.map(|c| c.to_owned());
+    let db_path = db_path.to_owned();
+    let pool_path = pool_path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let index = ArchiveIndex::load(&labels, catalog.as_deref(), &db_path)?;
+        let mut new = Vec::new();
+        let mut archived = Vec::new();
+        for p in packages {
+            match index.locate(&p, &pool_path)? {
+                Some(location) => archived.push((p, location)),
+                None => new.push(p),
+            }
+        }
+        info!("{} packages are already archived", archived.len());
+
+        Ok((new, archived))
+    })
+    .await?
+}
+
+async fn generate_manifest(
+    packages: &[PackageMeta],
+    archived: &[(PackageMeta, String)],
+    db_path: &Path,
+) -> Result<()> {
     info!("Generating manifest ...");
     let db_path = db_path.to_owned();
-    let packages = packages.clone();
-    tokio::task::spawn_blocking(move || save_new_packages(db_path, &packages)).await??;
+    let packages = packages.to_owned();
+    let archived = archived.to_owned();
+    tokio::task::spawn_blocking(move || {
+        save_new_packages(&db_path, &packages)?;
+        save_archived_packages(&db_path, &archived)
+    })
+    .await??;
 
     Ok(())
 }
 
 async fn chunked_copy_files(
-    config: &Config,
+    journal: &Journal,
     packages: &[PackageMeta],
     count: &AtomicUsize,
     total_count: usize,
+    original_path: &Path,
     output_path: &Path,
 ) -> bool {
     let mut tasks = Vec::new();
-    let original_path = Path::new(&config.config.path);
     for p in packages.iter() {
-        tasks.push(backup_package(
-            &count,
-            total_count,
-            &p.filename,
-            output_path,
-            original_path,
-        ));
+        tasks.push(async move {
+            backup_package(
+                count,
+                total_count,
+                &p.filename,
+                &p.sha256,
+                output_path,
+                original_path,
+            )
+            .await?;
+            journal
+                .append(&JournalEntry::Done {
+                    filename: p.filename.clone(),
+                })
+                .await
+        });
     }
     info!("Moving files ...");
     let mut errored = false;
@@ -143,10 +805,122 @@ async fn chunked_copy_files(
     errored
 }
 
-async fn backup_package(
+#[derive(Debug, PartialEq, Eq)]
+enum MoveMethod {
+    Rename,
+    Reflink,
+    Copy,
+}
+
+/// Clones the extents of the file, on file systems that support it.
+fn reflink(src: &std::fs::File, dest: &std::fs::File) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Checks the file against the checksum from p-vector, and makes sure it
+/// has reached the disk.
+fn verify_file(path: &Path, sha256: &str) -> Result<()> {
+    let actual = sha256_file(path).with_context(|| format!("when hashing {}", path.display()))?;
+    if actual != sha256 {
+        bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            path.display(),
+            sha256,
+            actual
+        );
+    }
+    std::fs::File::open(path)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Checks that the file in the pool is still the planned one, and that its
+/// copy archived in an earlier batch is still intact, before deleting it.
+/// The copies on discs were checked against their MD5 checksums when
+/// planning, and can not be reached here.
+fn check_before_delete(path: &Path, sha256: &str, location: &str) -> Result<()> {
+    let actual = sha256_file(path).with_context(|| format!("when hashing {}", path.display()))?;
+    if actual != sha256 {
+        bail!(
+            "{} was changed since it was planned for deletion",
+            path.display()
+        );
+    }
+    if disc_of(location).is_some() {
+        return Ok(());
+    }
+    let archived = sha256_file(Path::new(location))
+        .with_context(|| format!("when hashing the archived copy {}", location))?;
+    if archived != sha256 {
+        bail!(
+            "The archived copy {} of {} was changed, keeping it in the pool",
+            location,
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Moves the file with `rename(2)` if the target directory is on the same
+/// device. Otherwise copies it with a reflink or `copy_file_range(2)` into
+/// a temporary file, which is renamed into place once it is verified,
+/// leaving the source alone. The copy keeps the mtime and permissions.
+fn move_or_copy_file(src_path: &Path, dest_path: &Path, sha256: &str) -> Result<MoveMethod> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(src_path)?;
+    let target_dir = dest_path.parent().unwrap_or(Path::new("."));
+    if std::fs::metadata(target_dir)?.dev() == metadata.dev() {
+        std::fs::rename(src_path, dest_path)?;
+        std::fs::File::open(target_dir)?.sync_all()?;
+        return Ok(MoveMethod::Rename);
+    }
+    let mut temp_path = dest_path.as_os_str().to_owned();
+    temp_path.push(".partial");
+    let temp_path = PathBuf::from(temp_path);
+    let src = std::fs::File::open(src_path)?;
+    let temp = std::fs::File::create(&temp_path)?;
+    let method = if reflink(&src, &temp).is_ok() {
+        MoveMethod::Reflink
+    } else {
+        drop(temp);
+        // uses copy_file_range(2) where possible
+        std::fs::copy(src_path, &temp_path)?;
+        MoveMethod::Copy
+    };
+    let temp = std::fs::File::options().write(true).open(&temp_path)?;
+    temp.set_permissions(metadata.permissions())?;
+    temp.set_times(
+        std::fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )?;
+    if let Err(e) = verify_file(&temp_path, sha256) {
+        std::fs::remove_file(&temp_path).ok();
+        return Err(e);
+    }
+    std::fs::rename(&temp_path, dest_path)?;
+    std::fs::File::open(target_dir)?.sync_all()?;
+
+    Ok(method)
+}
+
+/// Moves the file from `original_path` to `output_path`. If the file is
+/// already there, e.g. from an interrupted run, it is checked and the
+/// source is deleted, so that it is safe to run again.
+pub async fn backup_package(
     count: &AtomicUsize,
     total_count: usize,
     filename: &str,
+    sha256: &str,
     output_path: &Path,
     original_path: &Path,
 ) -> Result<()> {
@@ -162,18 +936,33 @@ async fn backup_package(
         let original_path = original_path.join(path);
         let dest_path = output_path.join(path);
         if tokio::fs::metadata(&dest_path).await.is_ok() {
-            info!("Skipping, already copied: {}", filename);
+            let (dest, sum) = (dest_path.clone(), sha256.to_owned());
+            tokio::task::spawn_blocking(move || verify_file(&dest, &sum))
+                .await?
+                .with_context(|| format!("when verifying {}, which is already there", filename))?;
+            if tokio::fs::metadata(&original_path).await.is_ok() {
+                tokio::fs::remove_file(&original_path)
+                    .await
+                    .with_context(|| format!("when deleting {}", original_path.display()))?;
+            }
+            info!("Skipping, already moved: {}", filename);
             return Ok(());
         }
         tokio::fs::create_dir_all(&target_dir)
             .await
             .with_context(|| format!("when creating target directory {}", target_dir.display()))?;
-        tokio::fs::copy(&original_path, &dest_path)
-            .await
-            .with_context(|| format!("when copying {}", original_path.display()))?;
-        tokio::fs::remove_file(&original_path)
-            .await
-            .with_context(|| format!("when deleting {}", original_path.display()))?;
+        // never delete the source unless the copy is intact
+        let method = {
+            let (src, dest, sum) = (original_path.clone(), dest_path.clone(), sha256.to_owned());
+            tokio::task::spawn_blocking(move || move_or_copy_file(&src, &dest, &sum))
+                .await?
+                .with_context(|| format!("when moving {}", original_path.display()))?
+        };
+        if method != MoveMethod::Rename {
+            tokio::fs::remove_file(&original_path)
+                .await
+                .with_context(|| format!("when deleting {}", original_path.display()))?;
+        }
         info!("Successfully moved {}", filename);
     } else {
         error!("No parent directory: {}", filename);
@@ -181,3 +970,294 @@ async fn backup_package(
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_backup_package() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = crate::testing::TempDir::new("backup")?;
+    let pool = root.join("debs");
+    let output = root.join("archive-20240101");
+    std::fs::create_dir_all(pool.join("pool/stable/main/w"))?;
+    let filename = "pool/stable/main/w/webkit2gtk_2.20.0-0_amd64.deb";
+    std::fs::write(pool.join(filename), "webkit2gtk")?;
+    let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1514764800);
+    std::fs::File::options()
+        .write(true)
+        .open(pool.join(filename))?
+        .set_modified(mtime)?;
+    let sha256 = sha256_file(&pool.join(filename))?;
+    let count = AtomicUsize::new(1);
+    backup_package(&count, 1, filename, &sha256, &output, &pool).await?;
+    let moved = !pool.join(filename).exists();
+    let moved_mtime = std::fs::metadata(output.join(filename))?.modified()?;
+    // running again is fine
+    backup_package(&count, 1, filename, &sha256, &output, &pool).await?;
+    let mismatch = backup_package(&count, 1, filename, &"0".repeat(64), &output, &pool).await;
+    // tmpfs is a different file system from the one holding the temporary directory
+    let shm = Path::new("/dev/shm").join(format!("aosc-archive-backup-{}", std::process::id()));
+    let copied = if std::fs::create_dir(&shm).is_ok() {
+        std::fs::write(shm.join("a.deb"), "a")?;
+        std::fs::set_permissions(shm.join("a.deb"), std::fs::Permissions::from_mode(0o600))?;
+        std::fs::File::options()
+            .write(true)
+            .open(shm.join("a.deb"))?
+            .set_modified(mtime)?;
+        let sha256 = sha256_file(&shm.join("a.deb"))?;
+        let corrupted = move_or_copy_file(&shm.join("a.deb"), &root.join("a.deb"), &"0".repeat(64));
+        let not_copied = !root.join("a.deb").exists() && !root.join("a.deb.partial").exists();
+        let method = move_or_copy_file(&shm.join("a.deb"), &root.join("a.deb"), &sha256)?;
+        let metadata = std::fs::metadata(root.join("a.deb"))?;
+        let kept = shm.join("a.deb").exists();
+        std::fs::remove_dir_all(&shm)?;
+        Some((
+            corrupted.is_err() && not_copied,
+            method,
+            kept,
+            metadata.modified()?,
+            metadata.permissions().mode() & 0o777,
+        ))
+    } else {
+        None
+    };
+    drop(root);
+    assert!(moved);
+    assert_eq!(moved_mtime, mtime);
+    assert!(mismatch.is_err());
+    if let Some((corrupted, method, kept, modified, mode)) = copied {
+        assert!(corrupted);
+        assert_ne!(method, MoveMethod::Rename);
+        assert!(kept);
+        assert_eq!(modified, mtime);
+        assert_eq!(mode, 0o600);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_archived() -> Result<()> {
+    let root = crate::testing::TempDir::new("delete")?;
+    let pool = root.join("debs");
+    let earlier = root.join("archive-20230101");
+    let output = root.join("archive-20240101");
+    for dir in [&pool, &earlier] {
+        std::fs::create_dir_all(dir.join("pool/stable/main/w"))?;
+    }
+    std::fs::create_dir_all(&output)?;
+    let mut archived = Vec::new();
+    for arch in ["amd64", "arm64", "riscv64"] {
+        let package = PackageMeta {
+            sha256: format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(arch)),
+            ..crate::testing::package("webkit2gtk", "2.20.0-0", arch)
+        };
+        std::fs::write(pool.join(&package.filename), arch)?;
+        std::fs::write(earlier.join(&package.filename), arch)?;
+        let location = earlier.join(&package.filename).display().to_string();
+        archived.push((package, location));
+    }
+    let journal = Journal::create(&output.join(JOURNAL_NAME)).await?;
+    // the archived copy is broken
+    std::fs::write(earlier.join(&archived[1].0.filename), "broken")?;
+    let broken_copy = move_packages(&journal, &pool, &output, &[], &archived).await;
+    let kept = pool.join(&archived[1].0.filename).exists();
+    // the file in the pool was replaced after planning
+    std::fs::write(earlier.join(&archived[1].0.filename), "arm64")?;
+    std::fs::write(pool.join(&archived[2].0.filename), "rebuilt")?;
+    let changed = move_packages(&journal, &pool, &output, &[], &archived).await;
+    let deleted = [&archived[0], &archived[1]]
+        .iter()
+        .all(|(p, _)| !pool.join(&p.filename).exists());
+    let replaced_kept = pool.join(&archived[2].0.filename).exists();
+    drop(root);
+    assert!(broken_copy.is_err_and(|e| e.to_string().contains("was changed")));
+    assert!(kept);
+    assert!(changed.is_err_and(|e| e.to_string().contains("since it was planned")));
+    assert!(deleted);
+    assert!(replaced_kept);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_and_rollback() -> Result<()> {
+    let root = crate::testing::TempDir::new("resume")?;
+    let pool = root.join("debs");
+    let output = root.join("archive-20240101");
+    let database = output.join("labels-20240101.db");
+    std::fs::create_dir_all(pool.join("pool/stable/main/w"))?;
+    std::fs::create_dir_all(&output)?;
+    let mut packages = Vec::new();
+    for arch in ["amd64", "arm64", "riscv64"] {
+        let filename = format!("pool/stable/main/w/webkit2gtk_2.20.0-0_{}.deb", arch);
+        std::fs::write(pool.join(&filename), arch)?;
+        packages.push(PackageMeta {
+            sha256: sha256_file(&pool.join(&filename))?,
+            size: arch.len() as i64,
+            ..crate::testing::package("webkit2gtk", "2.20.0-0", arch)
+        });
+    }
+    // interrupted after planning, and moving the first package
+    let journal_path = output.join(JOURNAL_NAME);
+    let journal = Journal::create(&journal_path).await?;
+    journal
+        .append(&JournalEntry::Begin {
+            pool: pool.clone(),
+            output: output.clone(),
+            database: database.clone(),
+        })
+        .await?;
+    for p in packages.iter() {
+        journal
+            .append(&JournalEntry::Move { package: p.clone() })
+            .await?;
+    }
+    // only left on a disc once deleted from the pool
+    let filename = "pool/stable/main/w/webkit2gtk_2.20.0-0_loongson3.deb";
+    std::fs::write(pool.join(filename), "loongson3")?;
+    let on_disc = PackageMeta {
+        sha256: sha256_file(&pool.join(filename))?,
+        size: 9,
+        ..crate::testing::package("webkit2gtk", "2.20.0-0", "loongson3")
+    };
+    journal
+        .append(&JournalEntry::Delete {
+            package: on_disc.clone(),
+            location: "disc-48:./Repository/stable/main/w/webkit2gtk_2.20.0-0_loongson3.deb"
+                .to_owned(),
+        })
+        .await?;
+    std::fs::create_dir_all(output.join("pool/stable/main/w"))?;
+    std::fs::rename(
+        pool.join(&packages[0].filename),
+        output.join(&packages[0].filename),
+    )?;
+    drop(journal);
+    resume_retirement(&journal_path).await?;
+    let resumed = packages
+        .iter()
+        .all(|p| !pool.join(&p.filename).exists() && output.join(&p.filename).exists());
+    let deleted = !pool.join(filename).exists();
+    let recorded = is_recorded(&database, &packages[2].filename)?;
+    let needs_disc = rollback_retirement(&journal_path).await;
+    let rolled_back = packages
+        .iter()
+        .all(|p| pool.join(&p.filename).exists() && !output.join(&p.filename).exists());
+    // copied back from the disc by hand
+    std::fs::write(pool.join(filename), "loongson3")?;
+    rollback_retirement(&journal_path).await?;
+    let archived = crate::db::load_archived_checksums(&database)?;
+    let resume_again = resume_retirement(&journal_path).await;
+    drop(root);
+    assert!(resumed);
+    assert!(deleted);
+    assert!(recorded);
+    assert!(needs_disc.is_err_and(|e| e.to_string().contains("only archived on discs")));
+    assert!(rolled_back);
+    assert!(archived.is_empty());
+    assert!(resume_again.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_keep_protected_companions() {
+    use crate::testing::package;
+
+    let companion = |kernel: &str, p: &PackageMeta| KernelCompanion {
+        kernel: kernel.to_owned(),
+        repo: p.repo.clone(),
+        package: p.package.clone(),
+        version: p.version.clone(),
+        filename: p.filename.clone(),
+    };
+    let headers = package("linux-kernel-headers-6.1.69", "6.1.69-0", "amd64");
+    let nvidia = package("nvidia-kmod", "535.1+6.1.69-0", "amd64");
+    let zfs = package("zfs-kmod", "2.2.0+6.6.9", "amd64");
+    let rtl = package("rtl8821ce-6.6.9", "1.0-0", "amd64");
+    let mut packages = vec![
+        (headers.clone(), Reason::KernelCompanion),
+        (nvidia.clone(), Reason::KernelCompanion),
+        (nvidia.clone(), Reason::Superseded),
+        (zfs.clone(), Reason::KernelCompanion),
+    ];
+    let mut companions = vec![
+        companion("linux-kernel-6.1.69", &headers),
+        companion("linux-kernel-6.1.69", &nvidia),
+        companion("linux-kernel-6.6.9", &zfs),
+        companion("linux-kernel-6.6.9", &rtl),
+    ];
+    let protected = vec![
+        (
+            package("linux-kernel-6.1.69", "6.1.69-0", "amd64"),
+            "linux-kernel-6.1.*: fallback".to_owned(),
+        ),
+        (rtl.clone(), "rtl*: out of tree".to_owned()),
+    ];
+    let kept = keep_protected_companions(&mut packages, &mut companions, &protected);
+    let kept = kept
+        .iter()
+        .map(|(p, why)| format!("{}: {}", p.package, why))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kept,
+        vec![
+            "linux-kernel-headers-6.1.69: built for linux-kernel-6.1.69, which is protected",
+            "nvidia-kmod: built for linux-kernel-6.1.69, which is protected",
+        ]
+    );
+    assert_eq!(packages, vec![(zfs, Reason::KernelCompanion)]);
+    assert_eq!(
+        companions
+            .iter()
+            .map(|c| c.package.as_str())
+            .collect::<Vec<_>>(),
+        vec!["zfs-kmod"]
+    );
+}
+
+#[test]
+fn test_keep_needed() {
+    use crate::testing::package;
+
+    let version = |p: &PackageMeta| PoolVersion {
+        package: p.package.clone(),
+        version: p.version.clone(),
+        architecture: p.architecture.clone(),
+        repo: p.repo.clone(),
+        filename: p.filename.clone(),
+    };
+    let companion = |p: &PackageMeta| KernelCompanion {
+        kernel: "linux-kernel-6.1.69".to_owned(),
+        repo: p.repo.clone(),
+        package: p.package.clone(),
+        version: p.version.clone(),
+        filename: p.filename.clone(),
+    };
+    let nvidia = package("nvidia-kmod", "535.1+6.1.69-0", "amd64");
+    let headers = package("linux-kernel-headers-6.1.69", "6.1.69-0", "amd64");
+    let driver = package("nvidia-driver", "535.1-0", "amd64");
+    let mut packages = vec![
+        (nvidia.clone(), Reason::KernelCompanion),
+        (headers.clone(), Reason::KernelCompanion),
+    ];
+    let mut companions = vec![companion(&nvidia), companion(&headers)];
+    let versions = vec![version(&nvidia), version(&headers), version(&driver)];
+    let dependencies = vec![Dependency {
+        package: driver.package.clone(),
+        version: driver.version.clone(),
+        architecture: driver.architecture.clone(),
+        repo: driver.repo.clone(),
+        filename: driver.filename.clone(),
+        value: "nvidia-kmod (= 535.1+6.1.69-0)".to_owned(),
+    }];
+    let kept = keep_needed(&mut packages, &mut companions, &versions, &dependencies);
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].0.package, "nvidia-kmod");
+    assert!(kept[0].1.contains("nvidia-driver"), "{}", kept[0].1);
+    assert_eq!(packages, vec![(headers, Reason::KernelCompanion)]);
+    assert_eq!(
+        companions
+            .iter()
+            .map(|c| c.package.as_str())
+            .collect::<Vec<_>>(),
+        vec!["linux-kernel-headers-6.1.69"]
+    );
+}
@@ -0,0 +1,266 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+
+use crate::catalog::disc_of;
+use crate::cli::RollbackArgs;
+use crate::db::{load_batch_packages, mark_rolled_back, record_history};
+use crate::manifest::sha256_file;
+use crate::restore::copy_verified;
+use crate::retire::{backup_package, load_config};
+
+/// What became of a package being put back into the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutBack {
+    /// The package is in the pool again
+    Done,
+    /// No copy of the package is left
+    Missing,
+    /// The package is only archived on this disc, and has to be copied back
+    /// from it by hand
+    OnDisc(i64),
+}
+
+/// Puts the package back into the pool, either moving it back from the
+/// archive directory of its batch, or copying it from where it had already
+/// been archived.
+pub async fn put_back(
+    count: &AtomicUsize,
+    total_count: usize,
+    filename: &str,
+    sha256: &str,
+    location: Option<&str>,
+    pool_path: &Path,
+    archive_dir: &Path,
+) -> Result<PutBack> {
+    let dest = pool_path.join(filename);
+    let Some(location) = location else {
+        if !archive_dir.join(filename).exists() && !dest.exists() {
+            return Ok(PutBack::Missing);
+        }
+        // the same move, in the other direction
+        backup_package(count, total_count, filename, sha256, pool_path, archive_dir).await?;
+        return Ok(PutBack::Done);
+    };
+    let disc = disc_of(location);
+    info!(
+        "[{}/{}] Copying {} back from {} ... ",
+        count.fetch_add(1, Ordering::SeqCst),
+        total_count,
+        filename,
+        location
+    );
+    let (src, sha256) = (PathBuf::from(location), sha256.to_owned());
+    tokio::task::spawn_blocking(move || {
+        if dest.exists() {
+            let actual = sha256_file(&dest)?;
+            if actual != sha256 {
+                bail!(
+                    "{} exists in the pool with a different checksum",
+                    dest.display()
+                );
+            }
+            return Ok(PutBack::Done);
+        }
+        if let Some(disc) = disc {
+            return Ok(PutBack::OnDisc(disc));
+        }
+        if !src.is_file() {
+            return Ok(PutBack::Missing);
+        }
+        copy_verified(&src, &dest, &sha256)?;
+
+        Ok(PutBack::Done)
+    })
+    .await?
+}
+
+/// What a rollback of a batch did.
+#[derive(Debug, Default)]
+struct Outcome {
+    /// Number of packages put back in the pool
+    rolled_back: usize,
+    /// Files with no copy left
+    missing: Vec<String>,
+    /// Files only archived on discs, and the locations of their copies
+    on_disc: Vec<(String, String)>,
+}
+
+/// Puts every package of the batch back in the pool.
+async fn rollback_batch(
+    db_path: &Path,
+    archive_dir: &Path,
+    pool_path: &Path,
+    dry_run: bool,
+) -> Result<Outcome> {
+    let packages = {
+        let path = db_path.to_owned();
+        tokio::task::spawn_blocking(move || load_batch_packages(path))
+            .await?
+            .with_context(|| format!("when reading {}", db_path.display()))?
+    };
+    let packages = packages
+        .into_iter()
+        .filter(|p| p.status != "rolled-back")
+        .collect::<Vec<_>>();
+    let total_count = packages.len();
+    info!(
+        "Rolling back {} packages from {} ...",
+        total_count,
+        archive_dir.display()
+    );
+    if dry_run {
+        for p in packages.iter() {
+            match &p.location {
+                Some(location) => info!("{}: copy back from {}", p.package.filename, location),
+                None => info!("{}: move back", p.package.filename),
+            }
+        }
+        info!("[DRY-RUN] {} packages would be rolled back", total_count);
+        return Ok(Outcome::default());
+    }
+    let count = AtomicUsize::new(1);
+    let mut outcome = Outcome::default();
+    let mut failed = 0;
+    for p in packages.iter() {
+        // a restored package is already in the pool, and the archived copy goes away
+        let location = match p.status.as_str() {
+            "already-archived" => p.location.as_deref(),
+            _ => None,
+        };
+        let r = put_back(
+            &count,
+            total_count,
+            &p.package.filename,
+            &p.package.sha256,
+            location,
+            pool_path,
+            archive_dir,
+        )
+        .await;
+        match r {
+            Ok(PutBack::Done) => {
+                mark_rolled_back(db_path, &p.package.filename)?;
+                outcome.rolled_back += 1;
+            }
+            Ok(PutBack::Missing) => outcome.missing.push(p.package.filename.clone()),
+            Ok(PutBack::OnDisc(_)) => outcome.on_disc.push((
+                p.package.filename.clone(),
+                location.unwrap_or_default().to_owned(),
+            )),
+            Err(e) => {
+                failed += 1;
+                error!("Error occurred while moving files: {:?}", e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "Failed to roll back {} packages, fix the errors and run again",
+            failed
+        );
+    }
+
+    Ok(outcome)
+}
+
+pub async fn rollback_action(args: &RollbackArgs) -> Result<()> {
+    let config = load_config(&args.config).await?;
+    let pool_path = Path::new(&config.config.path);
+    let db_path = Path::new(&args.database);
+    let archive_dir = match &args.input {
+        Some(input) => PathBuf::from(input),
+        None => db_path.parent().unwrap_or(Path::new(".")).to_owned(),
+    };
+    let Outcome {
+        rolled_back,
+        missing,
+        on_disc,
+    } = rollback_batch(db_path, &archive_dir, pool_path, args.dry_run).await?;
+    if args.dry_run {
+        return Ok(());
+    }
+    if !missing.is_empty() {
+        error!(
+            "{} packages are missing from the archive and the pool:",
+            missing.len()
+        );
+        for filename in missing.iter() {
+            error!(" - {}", filename);
+        }
+    }
+    if !on_disc.is_empty() {
+        warn!(
+            "{} packages are only archived on discs, copy them into the pool and run again:",
+            on_disc.len()
+        );
+        for (filename, location) in on_disc.iter() {
+            warn!(" - {} from {}", filename, location);
+        }
+    }
+    record_history(db_path, "rolled-back", rolled_back, missing.len())?;
+    info!(
+        "Rolled back {} packages, {} missing, {} on discs",
+        rolled_back,
+        missing.len(),
+        on_disc.len()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rollback_batch() -> Result<()> {
+    let root = crate::testing::TempDir::new("rollback")?;
+    let pool = root.join("debs");
+    let archive_dir = root.join("archive-20240101");
+    let earlier = root.join("archive-20230101");
+    let db_path = archive_dir.join("labels-20240101.db");
+    for dir in [&pool, &archive_dir, &earlier] {
+        std::fs::create_dir_all(dir.join("pool/stable/main/w"))?;
+    }
+    let package = |arch: &str| crate::db::PackageMeta {
+        sha256: format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(arch)),
+        size: arch.len() as i64,
+        ..crate::testing::package("webkit2gtk", "2.20.0-0", arch)
+    };
+    let (moved, lost, copied) = (package("amd64"), package("arm64"), package("riscv64"));
+    let on_disc = package("loongson3");
+    std::fs::write(archive_dir.join(&moved.filename), "amd64")?;
+    std::fs::write(earlier.join(&copied.filename), "riscv64")?;
+    crate::db::save_new_packages(&db_path, &[moved.clone(), lost.clone()])?;
+    let location = earlier.join(&copied.filename).display().to_string();
+    let disc_location = "disc-48:./Repository/stable/main/w/webkit2gtk_2.20.0-0_loongson3.deb";
+    crate::db::save_archived_packages(
+        &db_path,
+        &[
+            (copied.clone(), location),
+            (on_disc.clone(), disc_location.to_owned()),
+        ],
+    )?;
+    rollback_batch(&db_path, &archive_dir, &pool, true).await?;
+    let dry_run_moved = pool.join(&moved.filename).exists();
+    let outcome = rollback_batch(&db_path, &archive_dir, &pool, false).await?;
+    let in_pool = [&moved, &copied]
+        .iter()
+        .all(|p| pool.join(&p.filename).exists());
+    let earlier_kept = earlier.join(&copied.filename).exists();
+    let archive_emptied = !archive_dir.join(&moved.filename).exists();
+    // the packages rolled back are skipped the next time
+    let again = rollback_batch(&db_path, &archive_dir, &pool, false).await?;
+    drop(root);
+    assert!(!dry_run_moved);
+    assert_eq!(outcome.rolled_back, 2);
+    assert_eq!(outcome.missing, vec![lost.filename]);
+    assert_eq!(
+        outcome.on_disc,
+        vec![(on_disc.filename, disc_location.to_owned())]
+    );
+    assert!(in_pool);
+    assert!(earlier_kept);
+    assert!(archive_emptied);
+    assert_eq!(again.rolled_back, 0);
+    Ok(())
+}
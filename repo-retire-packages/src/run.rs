@@ -0,0 +1,435 @@
+//! The retirement workflow of the operators, which used to live in
+//! `contrib/do-retire`.
+//!
+//! A retirement is named after its date, with an optional revision to allow
+//! several of them on the same day. The dry-run saves the plan and a log,
+//! which also serve as the stamp required by the real retirement.
+
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+
+use crate::cli::{RetireArgs, RunAction, RunArgs};
+use crate::dbus::find_active_units;
+use crate::plan::Plan;
+use crate::retire::retire_action;
+
+/// The user owning the repository, who can access the database through
+/// PostgreSQL's peer authentication.
+const OPERATOR: &str = "repo";
+
+/// These services must not run, and these triggers must not be active,
+/// while retiring.
+const UNITS_TO_CHECK: &[&str] = &[
+    "repo-scan-mirror.service",
+    "repo-scan-mirror.timer",
+    "repo-scan-mirror.path",
+    "repo-push.path",
+    "repo-push.service",
+    "repo-clean-up.timer",
+    "repo-clean-up.service",
+];
+
+/// Where the log lines go: the terminal until the log file is opened, then
+/// the log file, and the terminal as well if `echo` is set.
+#[derive(Default)]
+struct LogState {
+    file: Option<std::fs::File>,
+    echo: bool,
+}
+
+#[derive(Clone, Default)]
+struct LogTarget(Arc<Mutex<LogState>>);
+
+impl LogTarget {
+    fn init() -> Self {
+        let target = Self::default();
+        target.0.lock().unwrap().echo = true;
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+            .target(env_logger::Target::Pipe(Box::new(target.clone())))
+            .init();
+
+        target
+    }
+
+    fn set_file(&self, file: std::fs::File, echo: bool) {
+        let mut state = self.0.lock().unwrap();
+        state.file = Some(file);
+        state.echo = echo;
+    }
+
+    fn set_echo(&self, echo: bool) {
+        self.0.lock().unwrap().echo = echo;
+    }
+
+    /// Writes to the log file only.
+    fn write_file(&self, content: &str) -> Result<()> {
+        if let Some(file) = self.0.lock().unwrap().file.as_mut() {
+            file.write_all(content.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for LogTarget {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(file) = state.file.as_mut() {
+            file.write_all(buf)?;
+        }
+        if state.echo {
+            std::io::stderr().write_all(buf)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(file) = state.file.as_mut() {
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Paths of a retirement, named after its date and revision.
+#[derive(Debug, PartialEq, Eq)]
+struct RunPaths {
+    date: String,
+    output: PathBuf,
+    database: PathBuf,
+    log_dir: PathBuf,
+}
+
+impl RunPaths {
+    fn new(prefix: &Path, date: &str, revision: Option<&str>) -> Self {
+        let date = match revision {
+            Some(revision) => format!("{}.{}", date, revision),
+            None => date.to_owned(),
+        };
+        let output = prefix.join(format!("archive-{}", date));
+
+        Self {
+            database: output.join(format!("labels-{}.db", date)),
+            output,
+            log_dir: prefix.join("retire-logs"),
+            date,
+        }
+    }
+
+    fn log(&self, action: RunAction) -> PathBuf {
+        let action = match action {
+            RunAction::Dryrun => "dryrun",
+            RunAction::Retire => "retire",
+        };
+        self.log_dir
+            .join(format!("archive-{}-{}.log", self.date, action))
+    }
+
+    fn plan(&self) -> PathBuf {
+        self.log_dir
+            .join(format!("archive-{}-plan.json", self.date))
+    }
+
+    fn report(&self) -> PathBuf {
+        self.log_dir
+            .join(format!("archive-{}-report.json", self.date))
+    }
+}
+
+/// Checks what the earlier runs left behind: a retirement needs a finished
+/// dry-run, and neither of them may be done twice.
+fn check_stamps(action: RunAction, paths: &RunPaths) -> Result<()> {
+    match action {
+        RunAction::Dryrun if paths.log(RunAction::Dryrun).exists() => {
+            error!("It looks like you have already performed a dry-run.");
+            bail!("You can perform a retirement now, or use -r option to specify a revision.");
+        }
+        RunAction::Dryrun => (),
+        RunAction::Retire if !paths.log(RunAction::Dryrun).exists() => {
+            bail!("A dry-run hasn't been performed yet. Please perform a dry-run first.");
+        }
+        RunAction::Retire if !paths.plan().exists() => {
+            bail!(
+                "The dry-run did not finish, see {} for details.",
+                paths.log(RunAction::Dryrun).display()
+            );
+        }
+        RunAction::Retire if paths.output.exists() => {
+            error!("It looks like you have already performed an retirement.");
+            bail!("If you still want to perform another retirement, please use -r option to specify a revision.");
+        }
+        RunAction::Retire => (),
+    }
+
+    Ok(())
+}
+
+/// The lines around the output of a run in its log.
+fn banner(title: &str, paths: &RunPaths, date: &str, invoked_by: &str) -> String {
+    let rule = format!("#{}", "-".repeat(76));
+    format!(
+        "{rule}\n# {} {}\n# Date: {}\n# Output: {}\n# Database File: {}\n# Invoked By: {}\n{rule}\n",
+        title,
+        paths.date,
+        date,
+        paths.output.display(),
+        paths.database.display(),
+        invoked_by,
+    )
+}
+
+/// Refuses to run unless invoked by a human as the repository user.
+fn check_operator() -> Result<()> {
+    let name = std::ffi::CString::new(OPERATOR)?;
+    // SAFETY: the name is a valid C string, and the entry is read right away
+    let uid = unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        (!passwd.is_null()).then(|| (*passwd).pw_uid)
+    };
+    if uid != Some(unsafe { libc::geteuid() }) {
+        error!("This command must be run as the '{}' user.", OPERATOR);
+        bail!("If you do not have sudo access, you should contact someone who does.");
+    }
+    if !std::io::stdin().is_terminal()
+        || !std::io::stdout().is_terminal()
+        || !std::io::stderr().is_terminal()
+    {
+        bail!("This command is meant to be run by a human.");
+    }
+
+    Ok(())
+}
+
+/// Refuses to run while the services touching the repository are running,
+/// or their triggers are active.
+async fn check_units() -> Result<()> {
+    info!("Checking for running services ...");
+    let conn = zbus::Connection::system().await?;
+    let active = find_active_units(&conn, UNITS_TO_CHECK).await?;
+    let (services, triggers): (Vec<_>, Vec<_>) =
+        active.iter().partition(|u| u.ends_with(".service"));
+    if !services.is_empty() {
+        error!("There are repo related services that are still running/active.");
+        error!("Please wait them to finish before performing any actions:");
+        for service in services.iter() {
+            error!("- {}", service);
+        }
+    }
+    if !triggers.is_empty() {
+        error!("There are repo related triggers that are still active.");
+        error!("Please disable them before proceeding:");
+        for trigger in triggers.iter() {
+            error!("- {}", trigger);
+        }
+        error!("You can run the following command to disable them:");
+        error!(
+            "  sudo systemctl stop {}",
+            triggers
+                .iter()
+                .map(|t| t.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    if !active.is_empty() {
+        bail!("Refusing to continue while repo related units are active");
+    }
+
+    Ok(())
+}
+
+/// Clones the ABBS tree if needed, and makes sure git can update it.
+fn prepare_abbs_tree(abbs_dir: &Path) -> Result<()> {
+    if !abbs_dir.exists() {
+        info!("Cloning ABBS tree ...");
+        let status = Command::new("git")
+            .arg("clone")
+            .arg("https://github.com/AOSC-Dev/aosc-os-abbs")
+            .arg(abbs_dir)
+            .status()?;
+        if !status.success() {
+            bail!("Failed to clone the ABBS tree into {}", abbs_dir.display());
+        }
+    }
+    // git refuses to work in a tree owned by someone else
+    info!("Checking operability of the ABBS tree ...");
+    let status = Command::new("git")
+        .arg("status")
+        .current_dir(abbs_dir)
+        .stdout(Stdio::null())
+        .status()?;
+    if !status.success() {
+        error!(
+            "Can not perform updates to the ABBS repository at {}.",
+            abbs_dir.display()
+        );
+        bail!("Please check the permissions.");
+    }
+
+    Ok(())
+}
+
+pub async fn run_action(args: &RunArgs) -> Result<()> {
+    let target = LogTarget::init();
+    check_operator()?;
+    let now = chrono::Local::now();
+    let date = now.format("%a %b %e %H:%M:%S %Z %Y").to_string();
+    let invoked_by = std::env::var("SUDO_USER").unwrap_or_else(|_| OPERATOR.to_owned());
+    let paths = RunPaths::new(
+        Path::new(&args.prefix),
+        &now.format("%Y%m%d").to_string(),
+        args.revision.as_deref(),
+    );
+    let log_path = paths.log(args.action);
+    std::fs::create_dir_all(&paths.log_dir)
+        .with_context(|| format!("when creating {}", paths.log_dir.display()))?;
+    info!("Output directory: {}", paths.output.display());
+    info!("Output SQLite Database: {}", paths.database.display());
+    info!("Log file: {}", log_path.display());
+    check_stamps(args.action, &paths)?;
+    check_units().await?;
+    if args.out_of_tree {
+        info!("Out-of-tree retirement enabled");
+    }
+    if args.kernel {
+        info!("Outdated kernel packages retirement enabled");
+    }
+    if args.topics {
+        info!("Merged topics retirement enabled");
+    }
+    if args.no_orphaned_dbg {
+        info!("Orphaned -dbg packages retirement disabled");
+    }
+    let abbs_dir = match &args.abbs_dir {
+        Some(abbs_dir) => PathBuf::from(abbs_dir),
+        None => {
+            PathBuf::from(std::env::var("HOME").context("HOME is not set")?).join("aosc-os-abbs")
+        }
+    };
+    prepare_abbs_tree(&abbs_dir)?;
+
+    let dry_run = args.action == RunAction::Dryrun;
+    if dry_run && args.expect_sha256.is_some() {
+        bail!("--expect-sha256 is for the retirement, the dry-run makes the plan");
+    }
+    let title = if dry_run {
+        "Package Retirement (Dry-Run)"
+    } else {
+        "Package Retirement"
+    };
+    let log = std::fs::File::create(&log_path)
+        .with_context(|| format!("when creating {}", log_path.display()))?;
+    info!(
+        "Performing {} ...",
+        if dry_run { "dry-run" } else { "retirement" }
+    );
+    // the dry-run is read from its log, the retirement is watched
+    target.set_file(log, !dry_run);
+    target.write_file(&banner(
+        &format!("Begin {}", title),
+        &paths,
+        &date,
+        &invoked_by,
+    ))?;
+    let retire_args = RetireArgs {
+        abbs_dir: Some(abbs_dir.display().to_string()),
+        inhibit: Vec::new(),
+        out_of_tree: args.out_of_tree,
+        with_kernel: args.kernel,
+        topics: args.topics,
+        orphaned_dbg: !args.no_orphaned_dbg,
+        config: Some(args.config.clone()),
+        output: Some(paths.output.display().to_string()),
+        dry_run,
+        database: Some(paths.database.display().to_string()),
+        labels: vec![args.prefix.clone()],
+        catalog: None,
+        resume: None,
+        rollback: None,
+        report: dry_run.then(|| paths.report().display().to_string()),
+        plan: Some(paths.plan().display().to_string()),
+        expect_sha256: args.expect_sha256.clone(),
+        protect: args.protect.clone(),
+        no_default_protect: args.no_default_protect,
+    };
+    if let Err(e) = retire_action(&retire_args).await {
+        error!("{:?}", e);
+        target.set_echo(true);
+        bail!(
+            "Failed to run the retirement, see log file {} for details.",
+            log_path.display()
+        );
+    }
+    target.write_file(&banner(
+        &format!("End {}", title),
+        &paths,
+        &date,
+        &invoked_by,
+    ))?;
+    target.set_echo(true);
+    if dry_run {
+        let (_, sha256) = Plan::load(&paths.plan())?;
+        info!("Done performing dry-run.");
+        info!("Please examine the log file and the report before proceeding:");
+        info!("- {}", log_path.display());
+        info!("- {}", paths.report().display());
+        info!(
+            "Then retire exactly this plan with --expect-sha256 {}",
+            sha256
+        );
+        return Ok(());
+    }
+    info!("Done.");
+
+    Ok(())
+}
+
+#[test]
+fn test_run_paths() -> Result<()> {
+    let prefix = crate::testing::TempDir::new("run")?;
+    let paths = RunPaths::new(&prefix, "20240101", Some("1"));
+    std::fs::create_dir_all(&paths.log_dir)?;
+    let fresh = (
+        check_stamps(RunAction::Dryrun, &paths).is_ok(),
+        check_stamps(RunAction::Retire, &paths).is_ok(),
+    );
+    // a dry-run which failed leaves only its log behind
+    std::fs::write(paths.log(RunAction::Dryrun), "")?;
+    let failed = check_stamps(RunAction::Retire, &paths).is_ok();
+    std::fs::write(paths.plan(), "")?;
+    let dry_run_done = (
+        check_stamps(RunAction::Dryrun, &paths).is_ok(),
+        check_stamps(RunAction::Retire, &paths).is_ok(),
+    );
+    std::fs::create_dir_all(&paths.output)?;
+    let retired = check_stamps(RunAction::Retire, &paths).is_ok();
+    let banner = banner("Begin Package Retirement", &paths, "Mon Jan  1", "repo");
+    let expected = (
+        prefix.join("archive-20240101.1"),
+        prefix.join("archive-20240101.1/labels-20240101.1.db"),
+        prefix.join("retire-logs/archive-20240101.1-dryrun.log"),
+    );
+    drop(prefix);
+    assert_eq!(
+        (
+            paths.output.clone(),
+            paths.database.clone(),
+            paths.log(RunAction::Dryrun)
+        ),
+        expected
+    );
+    assert_eq!(fresh, (true, false));
+    assert!(!failed);
+    assert_eq!(dry_run_done, (false, true));
+    assert!(!retired);
+    assert!(banner.contains("# Begin Package Retirement 20240101.1\n# Date: Mon Jan  1\n"));
+    Ok(())
+}
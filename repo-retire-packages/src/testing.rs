@@ -0,0 +1,63 @@
+//! Fixtures shared by the tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::db::PackageMeta;
+
+/// A package in the stable repository of its architecture, with an empty
+/// checksum and size.
+pub fn package(name: &str, version: &str, arch: &str) -> PackageMeta {
+    PackageMeta {
+        package: name.to_owned(),
+        sha256: String::new(),
+        size: 0,
+        filename: format!(
+            "pool/stable/main/{}/{}_{}_{}.deb",
+            &name[..1],
+            name,
+            version,
+            arch
+        ),
+        version: version.to_owned(),
+        architecture: arch.to_owned(),
+        repo: format!("{}/stable", arch),
+    }
+}
+
+/// An empty directory for the files of a test, removed once dropped, even
+/// when the test fails halfway.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> std::io::Result<Self> {
+        let path =
+            std::env::temp_dir().join(format!("aosc-archive-{}-{}", name, std::process::id()));
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
@@ -0,0 +1,151 @@
+//! Debian package versions, compared the way dpkg does.
+//!
+//! The archive databases get the comparison as the `debversion` collation,
+//! and as the `debversion_cmp(a, b)` function returning -1, 0 or 1, so that
+//! archived versions sort correctly without p-vector.
+
+use std::cmp::Ordering;
+
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Connection;
+
+/// Splits a version into its epoch, upstream version and revision.
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) if epoch.bytes().all(|c| c.is_ascii_digit()) => {
+            (epoch.parse().unwrap_or(0), rest)
+        }
+        _ => (0, version),
+    };
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+
+    (epoch, upstream, revision)
+}
+
+/// The upstream part of a version, without its epoch and revision.
+pub fn upstream_version(version: &str) -> &str {
+    split_version(version).1
+}
+
+/// The weight of a character outside the digits, with `~` sorting before
+/// everything, even the end of the string, and letters before the others.
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(b'~') => -1,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// Compares two upstream versions or revisions, alternating between the
+/// non-digit and digit parts, like `verrevcmp` in dpkg.
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() || !b.is_empty() {
+        while a.first().is_some_and(|c| !c.is_ascii_digit())
+            || b.first().is_some_and(|c| !c.is_ascii_digit())
+        {
+            let (ac, bc) = (order(a.first().copied()), order(b.first().copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            a = a.get(1..).unwrap_or_default();
+            b = b.get(1..).unwrap_or_default();
+        }
+        while a.first() == Some(&b'0') {
+            a = &a[1..];
+        }
+        while b.first() == Some(&b'0') {
+            b = &b[1..];
+        }
+        let mut first_diff = Ordering::Equal;
+        while let (Some(ac), Some(bc)) = (a.first(), b.first()) {
+            if !ac.is_ascii_digit() || !bc.is_ascii_digit() {
+                break;
+            }
+            first_diff = first_diff.then(ac.cmp(bc));
+            a = &a[1..];
+            b = &b[1..];
+        }
+        // the longer number is the larger one
+        if a.first().is_some_and(|c| c.is_ascii_digit()) {
+            return Ordering::Greater;
+        }
+        if b.first().is_some_and(|c| c.is_ascii_digit()) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Compares two package versions by epoch, upstream version and revision.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_version(a);
+    let (b_epoch, b_upstream, b_revision) = split_version(b);
+
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| compare_part(a_upstream, b_upstream))
+        .then_with(|| compare_part(a_revision, b_revision))
+}
+
+/// Registers the `debversion` collation and the `debversion_cmp` function.
+pub fn register_sqlite(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_collation("debversion", compare_versions)?;
+    conn.create_scalar_function(
+        "debversion_cmp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let a = ctx.get::<String>(0)?;
+            let b = ctx.get::<String>(1)?;
+            Ok(compare_versions(&a, &b) as i32)
+        },
+    )
+}
+
+#[test]
+fn test_compare_versions() -> rusqlite::Result<()> {
+    use Ordering::*;
+
+    for (a, b, expected) in [
+        ("1.2-1", "1.2-1", Equal),
+        ("1.2-1", "1.10-1", Less),
+        ("1.2", "1.2-0", Equal),
+        ("1.0~rc1", "1.0", Less),
+        ("1.0~~", "1.0~", Less),
+        ("1.0", "1.0a", Less),
+        ("1.0a", "1.0+", Less),
+        ("1:0.1", "2.0", Greater),
+        ("0:2.0", "2.0", Equal),
+        ("2.0-1", "2.0-1.1", Less),
+        ("1.001", "1.1", Equal),
+        ("6.1.2-0", "6.1.10-0", Less),
+        ("2.36-1-1", "2.36-1-2", Less),
+    ] {
+        assert_eq!(compare_versions(a, b), expected, "{} vs {}", a, b);
+        assert_eq!(compare_versions(b, a), expected.reverse(), "{} vs {}", b, a);
+    }
+    let conn = Connection::open_in_memory()?;
+    register_sqlite(&conn)?;
+    conn.execute_batch(
+        "CREATE TABLE packages (version TEXT);
+INSERT INTO packages VALUES ('1.10-0'), ('1:0.1'), ('1.2-0'), ('1.2~rc1-0');",
+    )?;
+    let sorted = conn
+        .prepare("SELECT version FROM packages ORDER BY version COLLATE debversion")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    assert_eq!(sorted, vec!["1.2~rc1-0", "1.2-0", "1.10-0", "1:0.1"]);
+    let cmp: i32 = conn.query_row("SELECT debversion_cmp('2.0', '1:1.0')", [], |row| {
+        row.get(0)
+    })?;
+    assert_eq!(cmp, -1);
+    Ok(())
+}